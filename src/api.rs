@@ -0,0 +1,334 @@
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use std::collections::HashSet;
+use std::mem;
+use syn::{
+    Expr, Fields, FieldsNamed, File, ForeignItem, Ident, ImplItem, Item, ItemImpl, ItemStruct,
+    Stmt, UseTree, Visibility,
+};
+
+pub fn retain_public_api(syntax_tree: &mut File) {
+    let mut public_names = HashSet::new();
+    collect_public_names(&syntax_tree.items, &mut public_names);
+    let mut reexports = HashSet::new();
+    collect_reexports(&syntax_tree.items, &mut reexports);
+    let names = Names {
+        public: public_names,
+        reexports,
+    };
+    syntax_tree.items = public_items(mem::take(&mut syntax_tree.items), None, &names);
+}
+
+struct Names {
+    // Types and traits declared `pub`, wherever they are.
+    public: HashSet<String>,
+    // The names that `pub use` re-exports, and "module::*" for glob imports.
+    reexports: HashSet<String>,
+}
+
+// - Keep `pub` items in public modules, including re-exports
+// - Keep `pub` items of private modules that are re-exported by name or glob
+// - Keep impls whose trait or self type mentions a `pub` type or trait, even
+//   from private modules and `const _: () = { ... };` blocks
+// - Keep only the `pub` items of inherent impls, and the `pub` named fields of
+//   structs and unions
+//
+// `private_module` is the name of the module if it is not public.
+fn public_items(items: Vec<Item>, private_module: Option<&str>, names: &Names) -> Vec<Item> {
+    let public_module = private_module.is_none();
+    let glob =
+        private_module.is_some_and(|module| names.reexports.contains(&format!("{}::*", module)));
+    let mut public = Vec::new();
+    for item in items {
+        match item {
+            Item::Mod(mut item) => {
+                let name = item.ident.to_string();
+                let private_module = if public_module && is_pub(&item.vis) {
+                    None
+                } else {
+                    Some(name.as_str())
+                };
+                let mut has_content = false;
+                if let Some((_, content)) = &mut item.content {
+                    *content = public_items(mem::take(content), private_module, names);
+                    has_content = !content.is_empty();
+                }
+                if public_module || has_content {
+                    public.push(Item::Mod(item));
+                }
+            }
+            Item::Impl(item) => {
+                public.extend(public_impl(item, &names.public).map(Item::Impl));
+            }
+            Item::Const(mut item) if item.ident == "_" => {
+                let Expr::Block(expr) = &mut *item.expr else {
+                    continue;
+                };
+                let mut has_impl = false;
+                expr.block.stmts = mem::take(&mut expr.block.stmts)
+                    .into_iter()
+                    .filter_map(|stmt| match stmt {
+                        Stmt::Item(Item::Impl(item)) => {
+                            let item = public_impl(item, &names.public)?;
+                            has_impl = true;
+                            Some(Stmt::Item(Item::Impl(item)))
+                        }
+                        Stmt::Item(Item::Use(_) | Item::ExternCrate(_)) => Some(stmt),
+                        _ => None,
+                    })
+                    .collect();
+                if has_impl {
+                    public.push(Item::Const(item));
+                }
+            }
+            Item::ForeignMod(mut item) if public_module => {
+                item.items.retain(|item| match item {
+                    ForeignItem::Fn(item) => is_pub(&item.vis),
+                    ForeignItem::Static(item) => is_pub(&item.vis),
+                    ForeignItem::Type(item) => is_pub(&item.vis),
+                    _ => false,
+                });
+                if !item.items.is_empty() {
+                    public.push(Item::ForeignMod(item));
+                }
+            }
+            item if public_module => public.extend(public_item(item)),
+            item if glob
+                || item_ident(&item).is_some_and(|ident| names.reexports.contains(&ident)) =>
+            {
+                public.extend(public_item(item));
+            }
+            _ => {}
+        }
+    }
+    public
+}
+
+fn public_item(mut item: Item) -> Option<Item> {
+    // Private fields are not part of the API, only that there are some.
+    match &mut item {
+        Item::Struct(ItemStruct {
+            fields: Fields::Named(fields),
+            ..
+        }) => retain_public_fields(fields),
+        Item::Union(item) => retain_public_fields(&mut item.fields),
+        _ => {}
+    }
+
+    let vis = match &item {
+        Item::Const(item) => &item.vis,
+        Item::Enum(item) => &item.vis,
+        Item::ExternCrate(item) => &item.vis,
+        Item::Fn(item) => &item.vis,
+        Item::Static(item) => &item.vis,
+        Item::Struct(item) => &item.vis,
+        Item::Trait(item) => &item.vis,
+        Item::TraitAlias(item) => &item.vis,
+        Item::Type(item) => &item.vis,
+        Item::Union(item) => &item.vis,
+        Item::Use(item) => &item.vis,
+        _ => return None,
+    };
+    is_pub(vis).then_some(item)
+}
+
+fn item_ident(item: &Item) -> Option<String> {
+    let ident = match item {
+        Item::Const(item) => &item.ident,
+        Item::Enum(item) => &item.ident,
+        Item::Fn(item) => &item.sig.ident,
+        Item::Static(item) => &item.ident,
+        Item::Struct(item) => &item.ident,
+        Item::Trait(item) => &item.ident,
+        Item::TraitAlias(item) => &item.ident,
+        Item::Type(item) => &item.ident,
+        Item::Union(item) => &item.ident,
+        _ => return None,
+    };
+    Some(ident.to_string())
+}
+
+fn retain_public_fields(fields: &mut FieldsNamed) {
+    fields.named = mem::take(&mut fields.named)
+        .into_iter()
+        .filter(|field| is_pub(&field.vis))
+        .collect();
+}
+
+fn public_impl(mut item: ItemImpl, public_names: &HashSet<String>) -> Option<ItemImpl> {
+    let mut mentions_public = mentions(item.self_ty.to_token_stream(), public_names);
+    if let Some((path, _for)) = &item.trait_ {
+        mentions_public |= mentions(path.to_token_stream(), public_names);
+    }
+    if !mentions_public {
+        return None;
+    }
+
+    if item.trait_.is_none() {
+        item.items.retain(|item| match item {
+            ImplItem::Const(item) => is_pub(&item.vis),
+            ImplItem::Fn(item) => is_pub(&item.vis),
+            ImplItem::Type(item) => is_pub(&item.vis),
+            _ => false,
+        });
+        if item.items.is_empty() {
+            return None;
+        }
+    }
+
+    Some(item)
+}
+
+fn collect_public_names(items: &[Item], public_names: &mut HashSet<String>) {
+    for item in items {
+        let (vis, ident) = match item {
+            Item::Enum(item) => (&item.vis, &item.ident),
+            Item::Struct(item) => (&item.vis, &item.ident),
+            Item::Trait(item) => (&item.vis, &item.ident),
+            Item::TraitAlias(item) => (&item.vis, &item.ident),
+            Item::Type(item) => (&item.vis, &item.ident),
+            Item::Union(item) => (&item.vis, &item.ident),
+            Item::Mod(item) => {
+                if let Some((_, content)) = &item.content {
+                    collect_public_names(content, public_names);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if is_pub(vis) {
+            public_names.insert(ident.to_string());
+        }
+    }
+}
+
+fn collect_reexports(items: &[Item], reexports: &mut HashSet<String>) {
+    for item in items {
+        match item {
+            Item::Use(item) if is_pub(&item.vis) => {
+                collect_use_tree(&item.tree, None, reexports);
+            }
+            Item::Mod(item) => {
+                if let Some((_, content)) = &item.content {
+                    collect_reexports(content, reexports);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_use_tree(tree: &UseTree, module: Option<&Ident>, reexports: &mut HashSet<String>) {
+    match tree {
+        UseTree::Path(tree) => collect_use_tree(&tree.tree, Some(&tree.ident), reexports),
+        UseTree::Name(tree) => {
+            reexports.insert(tree.ident.to_string());
+        }
+        UseTree::Rename(tree) => {
+            reexports.insert(tree.ident.to_string());
+        }
+        UseTree::Glob(_) => {
+            if let Some(module) = module {
+                reexports.insert(format!("{}::*", module));
+            }
+        }
+        UseTree::Group(group) => {
+            for tree in &group.items {
+                collect_use_tree(tree, module, reexports);
+            }
+        }
+    }
+}
+
+fn mentions(tokens: TokenStream, public_names: &HashSet<String>) -> bool {
+    tokens.into_iter().any(|tt| match tt {
+        TokenTree::Ident(ident) => public_names.contains(&ident.to_string()),
+        TokenTree::Group(group) => mentions(group.stream(), public_names),
+        TokenTree::Punct(_) | TokenTree::Literal(_) => false,
+    })
+}
+
+fn is_pub(vis: &Visibility) -> bool {
+    match vis {
+        Visibility::Public(_) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_retain_public_api() {
+    let mut syntax_tree: File = syn::parse_quote! {
+        pub struct S {
+            pub a: u8,
+            pub(crate) b: u8,
+            c: u8,
+        }
+        pub struct T(pub u8, u8);
+        pub union U {
+            pub a: u8,
+            b: u16,
+        }
+        struct Private;
+        pub(crate) fn f() {}
+        pub fn g() {}
+        pub use crate::inner::Reexported;
+        pub(crate) use crate::inner::Internal;
+        use std::fmt;
+        mod inner {
+            pub struct Reexported;
+            pub struct Internal;
+        }
+        pub use self::glob::*;
+        mod glob {
+            pub fn h() {}
+            fn private() {}
+        }
+        impl S {
+            pub fn new() -> Self {}
+            pub(super) fn helper(&self) {}
+            fn private(&self) {}
+        }
+        impl Clone for S {
+            fn clone(&self) -> Self {}
+        }
+        impl Clone for Private {
+            fn clone(&self) -> Self {}
+        }
+        const _: () = {
+            impl fmt::Debug for S {}
+            impl fmt::Debug for Private {}
+        };
+    };
+    retain_public_api(&mut syntax_tree);
+    let expected: File = syn::parse_quote! {
+        pub struct S {
+            pub a: u8,
+        }
+        pub struct T(pub u8, u8);
+        pub union U {
+            pub a: u8,
+        }
+        pub fn g() {}
+        pub use crate::inner::Reexported;
+        mod inner {
+            pub struct Reexported;
+        }
+        pub use self::glob::*;
+        mod glob {
+            pub fn h() {}
+        }
+        impl S {
+            pub fn new() -> Self {}
+        }
+        impl Clone for S {
+            fn clone(&self) -> Self {}
+        }
+        const _: () = {
+            impl fmt::Debug for S {}
+        };
+    };
+    assert_eq!(
+        prettyplease::unparse(&syntax_tree),
+        prettyplease::unparse(&expected),
+    );
+}
@@ -0,0 +1,108 @@
+use crate::cmd::CommandExt as _;
+use crate::error::Result;
+use crate::manifest;
+use crate::metadata;
+use crate::opts::Expand;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+pub const CARGO_EXPAND_BUILD_SCRIPT: &str = "CARGO_EXPAND_BUILD_SCRIPT";
+pub const CARGO_EXPAND_BUILD_SCRIPT_UNPRETTY: &str = "CARGO_EXPAND_BUILD_SCRIPT_UNPRETTY";
+
+// Build scripts are compiled under a profile of their own, so that forcing
+// them to be recompiled does not throw away the user's regular artifacts.
+pub const PROFILE: &str = "expand-build-script";
+
+pub fn profile_config(args: &Expand) -> String {
+    let inherits = if let Some(profile) = &args.profile {
+        profile
+    } else if args.release {
+        "release"
+    } else {
+        "dev"
+    };
+    format!("profile.{}.inherits={:?}", PROFILE, inherits)
+}
+
+// Cargo does not invoke rustc for a build script that is already up to date,
+// so clean the selected package's artifacts from our profile to make sure the
+// rustc wrapper gets to see the build script being compiled.
+pub fn clean(args: &Expand) -> Result<bool> {
+    let metadata = metadata::load(args)?;
+    let Some(package) = metadata.selected_package(args) else {
+        let _ = writeln!(
+            io::stderr(),
+            "ERROR: --build-script requires selecting a single package with -p",
+        );
+        return Ok(false);
+    };
+    if package
+        .find_target("custom-build", "build-script-build")
+        .is_none()
+    {
+        let _ = writeln!(io::stderr(), "ERROR: {} has no build script", package.name);
+        return Ok(false);
+    }
+
+    let mut cmd = Command::new(crate::cargo_binary());
+    cmd.arg("clean");
+    cmd.arg("--quiet");
+    cmd.flag_value("--package", format!("{}@{}", package.name, package.version));
+    cmd.flag_value("--profile", PROFILE);
+    cmd.flag_value("--config", profile_config(args));
+    for kv in &args.config {
+        cmd.flag_value("--config", kv);
+    }
+    if manifest::is_script(args.manifest_path.as_deref()) {
+        cmd.arg("-Zscript");
+        cmd.env("RUSTC_BOOTSTRAP", "1");
+    }
+    if let Some(target_dir) = &args.target_dir {
+        cmd.flag_value("--target-dir", target_dir);
+    }
+    if let Some(manifest_path) = &args.manifest_path {
+        cmd.flag_value("--manifest-path", manifest_path);
+    }
+    if args.offline {
+        cmd.arg("--offline");
+    }
+    if args.frozen {
+        cmd.arg("--frozen");
+    }
+    cmd.stdin(Stdio::null());
+    Ok(cmd.status()?.success())
+}
+
+// Called from the rustc wrapper. If this is the selected package's build
+// script, write its expansion to $CARGO_EXPAND_BUILD_SCRIPT before cargo goes
+// on to compile it for real.
+pub fn expand(rustc: &Command, outfile: &OsStr) {
+    if env::var_os("CARGO_PRIMARY_PACKAGE").is_none() {
+        return;
+    }
+    let args: Vec<&OsStr> = rustc.get_args().collect();
+    if !args
+        .windows(2)
+        .any(|pair| pair[0] == "--crate-name" && pair[1] == "build_script_build")
+    {
+        return;
+    }
+
+    let unpretty = env::var_os(CARGO_EXPAND_BUILD_SCRIPT_UNPRETTY)
+        .unwrap_or_else(|| OsString::from("expanded"));
+    let mut arg_z_unpretty = OsString::from(crate::ARG_Z_UNPRETTY);
+    arg_z_unpretty.push(unpretty);
+
+    let mut cmd = Command::new(rustc.get_program());
+    cmd.args(args);
+    cmd.arg("-o");
+    cmd.arg(outfile);
+    cmd.arg(arg_z_unpretty);
+    cmd.env("RUSTC_BOOTSTRAP", "1");
+    // Diagnostics are reported by the real compilation.
+    cmd.stdout(Stdio::null());
+    cmd.stderr(Stdio::null());
+    let _ = cmd.status();
+}
@@ -0,0 +1,407 @@
+use crate::config::Config;
+use crate::manifest;
+use crate::metadata;
+use crate::opts::Expand;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::env;
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::SystemTime;
+
+// Where cargo-expand keeps what it has worked out in earlier runs, inside the
+// target directory. None if the target directory cannot be known without
+// asking cargo, or with --no-cache, in which case nothing is cached.
+pub fn dir(args: &Expand) -> Option<PathBuf> {
+    if args.no_cache {
+        return None;
+    }
+    Some(target_dir(args)?.join("cargo-expand"))
+}
+
+fn target_dir(args: &Expand) -> Option<PathBuf> {
+    let target_dir = if let Some(target_dir) = &args.target_dir {
+        target_dir.clone()
+    } else if let Some(target_dir) = env::var_os("CARGO_TARGET_DIR") {
+        PathBuf::from(target_dir)
+    } else if let Some(target_dir) = env::var_os("CARGO_BUILD_TARGET_DIR") {
+        PathBuf::from(target_dir)
+    } else if args
+        .config
+        .iter()
+        .any(|config| config.contains("target-dir"))
+    {
+        return None;
+    } else if let Some(target_dir) = config_target_dir().ok()? {
+        target_dir
+    } else {
+        workspace_root(args)?.join("target")
+    };
+    Some(target_dir)
+}
+
+// The build.target-dir setting of the closest cargo config file, looking in
+// the current directory and its ancestors, and then in $CARGO_HOME. A relative
+// path is relative to the directory containing the .cargo directory.
+fn config_target_dir() -> Result<Option<PathBuf>, ()> {
+    for path in config_files() {
+        let content = fs_err::read_to_string(&path).map_err(drop)?;
+        let table: toml::Table = content.parse().map_err(drop)?;
+        let Some(build) = table.get("build") else {
+            continue;
+        };
+        match build.get("target-dir") {
+            Some(toml::Value::String(target_dir)) => {
+                let cargo_dir = path.parent().ok_or(())?;
+                let base = cargo_dir.parent().ok_or(())?;
+                return Ok(Some(base.join(target_dir)));
+            }
+            Some(_) => return Err(()),
+            None => {}
+        }
+    }
+    Ok(None)
+}
+
+// The cargo config files that apply in the current directory, closest first.
+fn config_files() -> Vec<PathBuf> {
+    let Ok(cwd) = env::current_dir() else {
+        return Vec::new();
+    };
+    let cargo_home = home::cargo_home().ok();
+    let cargo_dirs = cwd
+        .ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home);
+    let mut files = Vec::new();
+    for cargo_dir in cargo_dirs {
+        for name in ["config.toml", "config"] {
+            let path = cargo_dir.join(name);
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+// Found without asking cargo, which is the slow part being avoided: the
+// closest enclosing directory whose Cargo.toml has a [workspace] table, or
+// else the package's own directory.
+fn workspace_root(args: &Expand) -> Option<PathBuf> {
+    if manifest::is_script(args.manifest_path.as_deref()) {
+        return None;
+    }
+    let manifest_path = manifest::find_cargo_manifest(args.manifest_path.as_deref()).ok()?;
+    let package_dir = manifest_path.parent()?;
+    let workspace_root = package_dir
+        .ancestors()
+        .find(|dir| has_workspace_table(&dir.join("Cargo.toml")))
+        .unwrap_or(package_dir);
+    Some(workspace_root.to_owned())
+}
+
+fn has_workspace_table(manifest_path: &Path) -> bool {
+    let Ok(content) = fs_err::read_to_string(manifest_path) else {
+        return false;
+    };
+    content
+        .parse::<toml::Table>()
+        .is_ok_and(|table| table.contains_key("workspace"))
+}
+
+const BOOTSTRAP_FILE: &str = "rustc-bootstrap.json";
+
+// Everything that the answer of needs_rustc_bootstrap depends on. The version
+// includes the commit hash, so installing another toolchain, or updating this
+// one, invalidates the cached answer.
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct BootstrapKey {
+    cargo: String,
+    rustc: String,
+    version: String,
+    rustc_wrapper: Option<String>,
+    rustc_workspace_wrapper: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BootstrapEntry {
+    key: BootstrapKey,
+    needs_rustc_bootstrap: bool,
+}
+
+impl BootstrapKey {
+    pub fn current() -> Option<Self> {
+        let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+        let mut cmd = Command::new(&rustc);
+        cmd.arg("-vV");
+        cmd.stdin(Stdio::null());
+        cmd.stderr(Stdio::null());
+        let output = cmd.output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(BootstrapKey {
+            cargo: crate::cargo_binary().to_string_lossy().into_owned(),
+            rustc,
+            version: String::from_utf8(output.stdout).ok()?,
+            rustc_wrapper: env::var("RUSTC_WRAPPER").ok(),
+            rustc_workspace_wrapper: env::var("RUSTC_WORKSPACE_WRAPPER").ok(),
+        })
+    }
+}
+
+pub fn load_bootstrap(dir: &Path, key: &BootstrapKey) -> Option<bool> {
+    let content = fs_err::read_to_string(dir.join(BOOTSTRAP_FILE)).ok()?;
+    let entry: BootstrapEntry = serde_json::from_str(&content).ok()?;
+    (entry.key == *key).then_some(entry.needs_rustc_bootstrap)
+}
+
+// Best effort; the probe just runs again next time if this fails.
+pub fn store_bootstrap(dir: &Path, key: BootstrapKey, needs_rustc_bootstrap: bool) {
+    let entry = BootstrapEntry {
+        key,
+        needs_rustc_bootstrap,
+    };
+    let Ok(content) = serde_json::to_string(&entry) else {
+        return;
+    };
+    if fs_err::create_dir_all(dir).is_ok() {
+        let _ = fs_err::write(dir.join(BOOTSTRAP_FILE), content);
+    }
+}
+
+// A previously formatted expansion, identified by everything that goes into
+// it: the cargo command line, the options for formatting the output, the
+// toolchain, and the size and modification time of every file in the
+// workspace and in path dependencies, including Cargo.lock.
+pub struct ExpansionKey {
+    path: PathBuf,
+}
+
+// Enough for switching back and forth between a few crates and options,
+// without keeping an expansion for every edit ever made.
+const MAX_EXPANSIONS: usize = 64;
+
+impl ExpansionKey {
+    pub fn new(
+        args: &Expand,
+        config: &Config,
+        cmd: &Command,
+        outfile: &Path,
+        rustfmt: Option<&Path>,
+    ) -> Option<Self> {
+        // Snippets and dependencies are expanded from a different temporary
+        // directory every time.
+        if args.generated {
+            return None;
+        }
+        let dir = dir(args)?;
+        let toolchain = BootstrapKey::current()?;
+
+        let mut hasher = DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        // Development builds all have the same version.
+        if let Ok(exe) = env::current_exe() {
+            fingerprint(&[exe], &[], &mut hasher);
+        }
+        for arg in cmd.get_args() {
+            if arg == outfile {
+                "<outfile>".hash(&mut hasher);
+            } else {
+                arg.hash(&mut hasher);
+            }
+        }
+        format!("{:?}", args).hash(&mut hasher);
+        config.hash(&mut hasher);
+        rustfmt.hash(&mut hasher);
+        serde_json::to_string(&toolchain).ok()?.hash(&mut hasher);
+        // Cargo takes config from CARGO_* variables, and rustc and rustup from
+        // RUST* ones.
+        let mut vars: Vec<_> = env::vars_os()
+            .filter(|(name, _value)| {
+                let name = name.to_string_lossy();
+                name.starts_with("CARGO_") || name.starts_with("RUST")
+            })
+            .collect();
+        vars.sort();
+        vars.hash(&mut hasher);
+        fingerprint(&config_files(), &[], &mut hasher);
+        // Build scripts and proc macros can read any file or environment
+        // variable, so their output cannot be known to be unchanged.
+        let sources = sources(args);
+        if sources.runs_build_code {
+            return None;
+        }
+        fingerprint(&sources.paths, &build_dirs(args, &sources), &mut hasher);
+
+        let file_name = format!("{:016x}.rs", hasher.finish());
+        Some(ExpansionKey {
+            path: dir.join(EXPANSIONS_DIR).join(file_name),
+        })
+    }
+
+    pub fn load(&self) -> Option<String> {
+        let content = fs_err::read_to_string(&self.path).ok()?;
+        // Keeps recently used entries from being pruned.
+        if let Ok(file) = File::options().append(true).open(&self.path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(content)
+    }
+
+    // Best effort, like store_bootstrap.
+    pub fn store(&self, content: &str) {
+        let Some(parent) = self.path.parent() else {
+            return;
+        };
+        if fs_err::create_dir_all(parent).is_ok() && fs_err::write(&self.path, content).is_ok() {
+            prune(parent);
+        }
+    }
+}
+
+const EXPANSIONS_DIR: &str = "expansions";
+
+// Entries whose sources have since changed are never looked up again, so only
+// the most recently used ones are kept.
+fn prune(dir: &Path) {
+    let Ok(entries) = fs_err::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    if entries.len() <= MAX_EXPANSIONS {
+        return;
+    }
+    entries.sort_by_key(|&(modified, _)| Reverse(modified));
+    for (_modified, path) in &entries[MAX_EXPANSIONS..] {
+        let _ = fs_err::remove_file(path);
+    }
+}
+
+pub struct Sources {
+    pub paths: Vec<PathBuf>,
+    // The target directory according to cargo metadata.
+    pub target_dir: Option<PathBuf>,
+    // Whether any package in the dependency graph has a build script or is a
+    // proc macro, or that is not known.
+    pub runs_build_code: bool,
+}
+
+// The directories of all packages that are not from a registry or git, i.e.
+// the workspace members and path dependencies, plus the workspace's own
+// manifest and lockfile. A cargo script is just the one file, rather than the
+// whole directory it is in.
+pub fn sources(args: &Expand) -> Sources {
+    let manifest_path = manifest::find_cargo_manifest(args.manifest_path.as_deref());
+    let metadata = metadata::load_with_dependencies(args).ok();
+    let runs_build_code = metadata.as_ref().is_none_or(|metadata| {
+        metadata.packages.iter().any(|package| {
+            package.targets.iter().any(|target| {
+                target
+                    .kind
+                    .iter()
+                    .any(|kind| kind == "custom-build" || kind == "proc-macro")
+            })
+        })
+    });
+    let target_dir = metadata
+        .as_ref()
+        .map(|metadata| metadata.target_directory.clone());
+    if manifest::is_script(args.manifest_path.as_deref()) {
+        return Sources {
+            paths: manifest_path.into_iter().collect(),
+            target_dir,
+            runs_build_code,
+        };
+    }
+
+    let mut paths: Vec<PathBuf> = match metadata {
+        Some(metadata) => {
+            let mut paths: Vec<PathBuf> = metadata
+                .packages
+                .iter()
+                .filter(|package| package.source.is_none())
+                .filter_map(|package| package.manifest_path.parent())
+                .map(Path::to_owned)
+                .collect();
+            // The root of a virtual workspace is not a package.
+            for file in ["Cargo.toml", "Cargo.lock"] {
+                paths.push(metadata.workspace_root.join(file));
+            }
+            paths
+        }
+        None => manifest_path
+            .ok()
+            .and_then(|path| path.parent().map(Path::to_owned))
+            .into_iter()
+            .collect(),
+    };
+
+    // A workspace root contains its members.
+    paths.sort();
+    paths.dedup_by(|dir, ancestor| dir.starts_with(ancestor));
+    Sources {
+        paths,
+        target_dir,
+        runs_build_code,
+    }
+}
+
+// Where builds write, which may well be inside the sources: the target
+// directory that cargo metadata reports, and the one that these args select,
+// which contains the cache.
+pub fn build_dirs(args: &Expand, sources: &Sources) -> Vec<PathBuf> {
+    let cwd = env::current_dir().unwrap_or_default();
+    sources
+        .target_dir
+        .iter()
+        .cloned()
+        .chain(target_dir(args))
+        .map(|dir| cwd.join(dir))
+        .collect()
+}
+
+// The path, size and modification time of every file among the sources, other
+// than in hidden directories and the excluded ones.
+pub fn fingerprint(sources: &[PathBuf], excluded: &[PathBuf], hasher: &mut DefaultHasher) {
+    for source in sources {
+        if source.is_dir() {
+            fingerprint_dir(source, excluded, hasher);
+        } else if let Ok(metadata) = source.metadata() {
+            source.hash(hasher);
+            metadata.len().hash(hasher);
+            metadata.modified().ok().hash(hasher);
+        }
+    }
+}
+
+fn fingerprint_dir(dir: &Path, excluded: &[PathBuf], hasher: &mut DefaultHasher) {
+    let Ok(entries) = fs_err::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+    entries.sort_by_key(fs_err::DirEntry::file_name);
+    for entry in entries {
+        let name = entry.file_name();
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            let hidden = name.to_string_lossy().starts_with('.');
+            if !hidden && !excluded.contains(&path) {
+                fingerprint_dir(&path, excluded, hasher);
+            }
+            continue;
+        }
+        path.hash(hasher);
+        metadata.len().hash(hasher);
+        metadata.modified().ok().hash(hasher);
+    }
+}
@@ -0,0 +1,715 @@
+use crate::frontmatter;
+use crate::metadata;
+use crate::opts::Expand;
+use proc_macro2::{Span, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    Arm, Attribute, Expr, Field, File, ForeignItem, ImplItem, Item, LitStr, Meta, Stmt, TraitItem,
+    Variant,
+};
+
+// Rustc discards comments, so the ones we show come from the original source
+// files. A comment is carried over onto the node that immediately follows it,
+// but only within items whose tokens survived macro expansion unchanged;
+// anything else would risk putting the comment next to unrelated code.
+//
+// Comments are represented in the syntax tree as #[comment = "..."]
+// attributes, which prettyplease prints as // and /* */ comments.
+
+pub fn crate_root(args: &Expand) -> Option<PathBuf> {
+    let metadata = metadata::load(args).ok()?;
+    let package = metadata.selected_package(args)?;
+    let target = package.selected_target(args)?;
+    Some(target.src_path.clone())
+}
+
+pub fn restore(syntax_tree: &mut File, crate_root: &Path) {
+    let mut module = Module::default();
+    let child_dir = crate_root.parent().unwrap_or(Path::new(""));
+    load_file(crate_root, child_dir, &mut module);
+    restore_items(&mut syntax_tree.items, &mut module);
+}
+
+// Rustfmt does not know about #[comment], so turn those back into comments
+// after formatting.
+pub fn render_rustfmt_output(formatted: &str) -> String {
+    let mut rendered = String::with_capacity(formatted.len());
+    for line in formatted.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        let attr = trimmed.trim_end();
+        let comment = attr
+            .strip_prefix("#[comment = ")
+            .or_else(|| attr.strip_prefix("#![comment = "))
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(|lit| syn::parse_str::<LitStr>(lit).ok())
+            .map(|lit| lit.value());
+        match comment {
+            Some(comment) if !comment.contains('\n') => {
+                rendered.push_str(indent);
+                rendered.push_str("//");
+                rendered.push_str(comment.trim_end());
+                rendered.push('\n');
+            }
+            Some(comment) => {
+                rendered.push_str(indent);
+                rendered.push_str("/*");
+                rendered.push_str(&comment);
+                rendered.push_str("*/\n");
+            }
+            None => rendered.push_str(line),
+        }
+    }
+    rendered
+}
+
+#[derive(Default)]
+struct Module {
+    items: HashMap<String, VecDeque<Original>>,
+    submodules: HashMap<String, Module>,
+}
+
+struct Original {
+    item: Item,
+    source: Rc<Source>,
+}
+
+struct Source {
+    comments: Vec<Comment>,
+    // Byte offset of the start of every token, sorted.
+    token_starts: Vec<usize>,
+}
+
+struct Comment {
+    text: String,
+    // Byte offset of the first token after the comment.
+    next_token: usize,
+}
+
+fn load_file(path: &Path, child_dir: &Path, module: &mut Module) {
+    let Ok(content) = fs::read_to_string(path) else {
+        return;
+    };
+    let Some((file, source)) = parse_source(content) else {
+        return;
+    };
+    let file_dir = path.parent().unwrap_or(Path::new(""));
+    load_items(file.items, file_dir, child_dir, &Rc::new(source), module);
+}
+
+fn load_items(
+    items: Vec<Item>,
+    file_dir: &Path,
+    child_dir: &Path,
+    source: &Rc<Source>,
+    module: &mut Module,
+) {
+    for item in items {
+        if let Item::Mod(item_mod) = &item {
+            let name = item_mod.ident.to_string();
+            let submodule = module.submodules.entry(name.clone()).or_default();
+            let path_attr = path_attr(&item_mod.attrs);
+            if let Some((_, items)) = &item_mod.content {
+                let child_dir = child_dir.join(&name);
+                load_items(items.clone(), &child_dir, &child_dir, source, submodule);
+            } else {
+                let (path, grandchild_dir) = module_file(&name, path_attr, file_dir, child_dir);
+                load_file(&path, &grandchild_dir, submodule);
+            }
+        }
+        if let Some(key) = item_key(&item) {
+            module.items.entry(key).or_default().push_back(Original {
+                item,
+                source: Rc::clone(source),
+            });
+        }
+    }
+}
+
+// The file containing an out-of-line module, and the directory containing its
+// own out-of-line submodules.
+pub fn module_file(
+    name: &str,
+    path_attr: Option<String>,
+    file_dir: &Path,
+    child_dir: &Path,
+) -> (PathBuf, PathBuf) {
+    if let Some(path_attr) = path_attr {
+        let path = file_dir.join(path_attr);
+        let grandchild_dir = path.parent().unwrap_or(Path::new("")).to_owned();
+        (path, grandchild_dir)
+    } else {
+        let grandchild_dir = child_dir.join(name);
+        let path = child_dir.join(format!("{}.rs", name));
+        if path.exists() {
+            (path, grandchild_dir)
+        } else {
+            (grandchild_dir.join("mod.rs"), grandchild_dir)
+        }
+    }
+}
+
+pub fn path_attr(attrs: &[Attribute]) -> Option<String> {
+    attrs.iter().find_map(|attr| match &attr.meta {
+        Meta::NameValue(meta) if meta.path.is_ident("path") => match &meta.value {
+            Expr::Lit(expr) => match &expr.lit {
+                syn::Lit::Str(lit) => Some(lit.value()),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+// Parse an original source file, with span locations relative to the file.
+pub fn parse_original(content: String) -> Option<File> {
+    parse_source(content).map(|(file, _source)| file)
+}
+
+fn parse_source(mut content: String) -> Option<(File, Source)> {
+    if content.starts_with('\u{feff}') {
+        content.drain(..'\u{feff}'.len_utf8());
+    }
+
+    // Blank out the shebang line, preserving byte offsets.
+    if content.starts_with("#!") && !content[2..].trim_start().starts_with('[') {
+        let end = content.find('\n').unwrap_or(content.len());
+        content.replace_range(..end, &" ".repeat(end));
+    }
+
+    // Likewise the frontmatter of a cargo script.
+    if let Some(range) = frontmatter::find(&content) {
+        let blank: String = content[range.clone()]
+            .chars()
+            .map(|ch| if ch == '\n' { ch } else { ' ' })
+            .collect();
+        content.replace_range(range, &blank);
+    }
+
+    let tokens = TokenStream::from_str(&content).ok()?;
+    let mut ranges = Vec::new();
+    token_ranges(tokens.clone(), &mut ranges);
+    ranges.sort_unstable();
+
+    let mut gaps = Vec::new();
+    let mut prev_end = 0;
+    for &(start, end) in &ranges {
+        if start > prev_end {
+            gaps.push((prev_end, start));
+        }
+        prev_end = prev_end.max(end);
+    }
+
+    let mut comments = Vec::new();
+    for (start, next_token) in gaps {
+        for text in scan_comments(&content[start..next_token]) {
+            comments.push(Comment {
+                text: text.to_owned(),
+                next_token,
+            });
+        }
+    }
+
+    let file: File = syn::parse2(tokens).ok()?;
+    let token_starts = ranges.iter().map(|&(start, _end)| start).collect();
+    let source = Source {
+        comments,
+        token_starts,
+    };
+    Some((file, source))
+}
+
+fn token_ranges(tokens: TokenStream, ranges: &mut Vec<(usize, usize)>) {
+    for tt in tokens {
+        match tt {
+            TokenTree::Group(group) => {
+                ranges.push(byte_range(group.span_open()));
+                token_ranges(group.stream(), ranges);
+                ranges.push(byte_range(group.span_close()));
+            }
+            tt => ranges.push(byte_range(tt.span())),
+        }
+    }
+}
+
+fn byte_range(span: Span) -> (usize, usize) {
+    let range = span.byte_range();
+    (range.start, range.end)
+}
+
+// Text in between tokens consists of only whitespace and non-doc comments.
+fn scan_comments(mut gap: &str) -> Vec<&str> {
+    let mut comments = Vec::new();
+    loop {
+        gap = gap.trim_start();
+        if let Some(rest) = gap.strip_prefix("//") {
+            let len = rest.find('\n').unwrap_or(rest.len());
+            comments.push(rest[..len].trim_end_matches('\r'));
+            gap = &rest[len..];
+        } else if gap.starts_with("/*") {
+            let len = block_comment_len(gap);
+            comments.push(&gap[2..len - 2]);
+            gap = &gap[len..];
+        } else {
+            return comments;
+        }
+    }
+}
+
+fn block_comment_len(comment: &str) -> usize {
+    let bytes = comment.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        if bytes[i] == b'/' && bytes[i + 1] == b'*' {
+            depth += 1;
+            i += 2;
+        } else if bytes[i] == b'*' && bytes[i + 1] == b'/' {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+pub fn item_key(item: &Item) -> Option<String> {
+    let (kind, name) = match item {
+        Item::Const(item) => ("const", item.ident.to_string()),
+        Item::Enum(item) => ("enum", item.ident.to_string()),
+        Item::ExternCrate(item) => ("extern crate", item.ident.to_string()),
+        Item::Fn(item) => ("fn", item.sig.ident.to_string()),
+        Item::Mod(item) => ("mod", item.ident.to_string()),
+        Item::Static(item) => ("static", item.ident.to_string()),
+        Item::Struct(item) => ("struct", item.ident.to_string()),
+        Item::Trait(item) => ("trait", item.ident.to_string()),
+        Item::TraitAlias(item) => ("trait", item.ident.to_string()),
+        Item::Type(item) => ("type", item.ident.to_string()),
+        Item::Union(item) => ("union", item.ident.to_string()),
+        Item::Use(item) => ("use", item.tree.to_token_stream().to_string()),
+        Item::Impl(item) => {
+            let generics = &item.generics;
+            let self_ty = &item.self_ty;
+            let header = if let Some((path, for_token)) = &item.trait_ {
+                quote!(#generics #path #for_token #self_ty)
+            } else {
+                quote!(#generics #self_ty)
+            };
+            ("impl", header.to_string())
+        }
+        _ => return None,
+    };
+    Some(format!("{} {}", kind, name))
+}
+
+pub fn impl_item_key(item: &ImplItem) -> Option<String> {
+    match item {
+        ImplItem::Const(item) => Some(format!("const {}", item.ident)),
+        ImplItem::Fn(item) => Some(format!("fn {}", item.sig.ident)),
+        ImplItem::Type(item) => Some(format!("type {}", item.ident)),
+        _ => None,
+    }
+}
+
+pub fn trait_item_key(item: &TraitItem) -> Option<String> {
+    match item {
+        TraitItem::Const(item) => Some(format!("const {}", item.ident)),
+        TraitItem::Fn(item) => Some(format!("fn {}", item.sig.ident)),
+        TraitItem::Type(item) => Some(format!("type {}", item.ident)),
+        _ => None,
+    }
+}
+
+fn restore_items(items: &mut [Item], module: &mut Module) {
+    for item in items {
+        let Some(key) = item_key(item) else {
+            continue;
+        };
+        let Some(mut original) = module.items.get_mut(&key).and_then(VecDeque::pop_front) else {
+            continue;
+        };
+        let source = &original.source;
+        if restore_node(item, &mut original.item, source, Scope::All) {
+            continue;
+        }
+        restore_node(item, &mut original.item, source, Scope::Root);
+        match (item, &mut original.item) {
+            (Item::Mod(item), Item::Mod(_)) => {
+                let submodule = module.submodules.get_mut(&item.ident.to_string());
+                if let (Some((_, items)), Some(submodule)) = (&mut item.content, submodule) {
+                    restore_items(items, submodule);
+                }
+            }
+            (Item::Impl(item), Item::Impl(original)) => {
+                restore_nested(&mut item.items, &mut original.items, source, impl_item_key);
+            }
+            (Item::Trait(item), Item::Trait(original)) => {
+                restore_nested(&mut item.items, &mut original.items, source, trait_item_key);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn restore_nested<T: Node>(
+    items: &mut [T],
+    originals: &mut [T],
+    source: &Source,
+    key: fn(&T) -> Option<String>,
+) {
+    let mut originals_by_key = HashMap::<String, VecDeque<&mut T>>::new();
+    for original in originals {
+        if let Some(key) = key(original) {
+            originals_by_key.entry(key).or_default().push_back(original);
+        }
+    }
+    for item in items {
+        let original = key(item)
+            .and_then(|key| originals_by_key.get_mut(&key))
+            .and_then(VecDeque::pop_front);
+        if let Some(original) = original {
+            if !restore_node(item, original, source, Scope::All) {
+                restore_node(item, original, source, Scope::Root);
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Scope {
+    // Only comments in front of the item itself.
+    Root,
+    // Also comments inside of the item.
+    All,
+}
+
+fn restore_node<T: Node>(
+    expanded: &mut T,
+    original: &mut T,
+    source: &Source,
+    scope: Scope,
+) -> bool {
+    if scope == Scope::All && fingerprint(expanded) != fingerprint(original) {
+        return false;
+    }
+
+    let mut anchors = Anchors {
+        source,
+        scope,
+        index: 0,
+        positions: HashMap::new(),
+        num_attrs: Vec::new(),
+    };
+    original.visit(&mut anchors);
+
+    let mut pending = BTreeMap::<usize, Vec<(usize, Attribute)>>::new();
+    for comment in &source.comments {
+        if let Some(&(node, position)) = anchors.positions.get(&comment.next_token) {
+            let text = &comment.text;
+            let attr: Attribute = syn::parse_quote!(#[comment = #text]);
+            pending.entry(node).or_default().push((position, attr));
+        }
+    }
+
+    let mut attach = Attach {
+        index: 0,
+        pending,
+        num_attrs: anchors.num_attrs,
+    };
+    expanded.visit(&mut attach);
+    true
+}
+
+// Everything other than attributes and commas, which the compiler's pretty
+// printer does not always reproduce faithfully.
+fn fingerprint<T: Node>(node: &T) -> Vec<String> {
+    let mut node = node.clone();
+    node.visit(&mut StripAttrs);
+    let mut fingerprint = Vec::new();
+    flatten(node.to_token_stream(), &mut fingerprint);
+    fingerprint
+}
+
+fn flatten(tokens: TokenStream, out: &mut Vec<String>) {
+    for tt in tokens {
+        match tt {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    proc_macro2::Delimiter::Parenthesis => ("(", ")"),
+                    proc_macro2::Delimiter::Brace => ("{", "}"),
+                    proc_macro2::Delimiter::Bracket => ("[", "]"),
+                    proc_macro2::Delimiter::None => ("", ""),
+                };
+                out.push(open.to_owned());
+                flatten(group.stream(), out);
+                out.push(close.to_owned());
+            }
+            TokenTree::Punct(punct) if punct.as_char() == ',' => {}
+            tt => out.push(tt.to_string()),
+        }
+    }
+}
+
+trait Node: ToTokens + Clone {
+    fn visit<V: VisitMut>(&mut self, visitor: &mut V);
+}
+
+impl Node for Item {
+    fn visit<V: VisitMut>(&mut self, visitor: &mut V) {
+        visitor.visit_item_mut(self);
+    }
+}
+
+impl Node for ImplItem {
+    fn visit<V: VisitMut>(&mut self, visitor: &mut V) {
+        visitor.visit_impl_item_mut(self);
+    }
+}
+
+impl Node for TraitItem {
+    fn visit<V: VisitMut>(&mut self, visitor: &mut V) {
+        visitor.visit_trait_item_mut(self);
+    }
+}
+
+struct StripAttrs;
+
+impl VisitMut for StripAttrs {
+    fn visit_attributes_mut(&mut self, attrs: &mut Vec<Attribute>) {
+        attrs.clear();
+    }
+}
+
+// Calls a function on the attributes of a node, which are the first ones
+// visited in any syntax tree node that has attributes.
+struct FirstAttrs<F>(Option<F>);
+
+impl<F: FnOnce(&mut Vec<Attribute>)> VisitMut for FirstAttrs<F> {
+    fn visit_attributes_mut(&mut self, attrs: &mut Vec<Attribute>) {
+        if let Some(f) = self.0.take() {
+            f(attrs);
+        }
+    }
+}
+
+// Records, for the start of every token that a comment could precede, which
+// node and attribute position the comment belongs to.
+struct Anchors<'a> {
+    source: &'a Source,
+    scope: Scope,
+    index: usize,
+    positions: HashMap<usize, (usize, usize)>,
+    num_attrs: Vec<usize>,
+}
+
+impl Anchors<'_> {
+    fn node<T: ToTokens>(&mut self, node: &mut T, visit: fn(&mut dyn VisitMut, &mut T)) {
+        let index = self.index;
+        self.index += 1;
+        if self.scope == Scope::Root && index > 0 {
+            return;
+        }
+
+        let mut starts = Vec::new();
+        let mut num_attrs = 0;
+        let mut after_attrs = None;
+        visit(
+            &mut FirstAttrs(Some(|attrs: &mut Vec<Attribute>| {
+                num_attrs = attrs.len();
+                for attr in attrs.iter() {
+                    let mut tokens = attr.to_token_stream().into_iter();
+                    if let Some(first) = tokens.next() {
+                        starts.push(first.span().byte_range().start);
+                    }
+                    if let Some(last) = tokens.last() {
+                        after_attrs = Some(last.span().byte_range().end);
+                    }
+                }
+            })),
+            node,
+        );
+
+        let body_start = match after_attrs {
+            Some(end) => {
+                let token_starts = &self.source.token_starts;
+                let i = token_starts.partition_point(|&start| start < end);
+                token_starts.get(i).copied()
+            }
+            None => node
+                .to_token_stream()
+                .into_iter()
+                .next()
+                .map(|first| first.span().byte_range().start),
+        };
+
+        for (position, start) in starts.into_iter().chain(body_start).enumerate() {
+            self.positions.entry(start).or_insert((index, position));
+        }
+        self.num_attrs.push(num_attrs);
+    }
+}
+
+// Inserts the comment attributes into the corresponding nodes of the expanded
+// syntax tree, which has the same shape as the original apart from attributes.
+struct Attach {
+    index: usize,
+    pending: BTreeMap<usize, Vec<(usize, Attribute)>>,
+    num_attrs: Vec<usize>,
+}
+
+impl Attach {
+    fn node<T>(&mut self, node: &mut T, visit: fn(&mut dyn VisitMut, &mut T)) {
+        let index = self.index;
+        self.index += 1;
+        let Some(comments) = self.pending.remove(&index) else {
+            return;
+        };
+        let original_num_attrs = self.num_attrs.get(index).copied().unwrap_or(0);
+        let insert_comments = move |attrs: &mut Vec<Attribute>| {
+            let mut insert = BTreeMap::<usize, Vec<Attribute>>::new();
+            for (position, comment) in comments {
+                let position = if position >= original_num_attrs {
+                    attrs.len()
+                } else {
+                    position.min(attrs.len())
+                };
+                insert.entry(position).or_default().push(comment);
+            }
+            let mut old = mem::take(attrs).into_iter();
+            for i in 0.. {
+                if let Some(comments) = insert.remove(&i) {
+                    attrs.extend(comments);
+                }
+                match old.next() {
+                    Some(attr) => attrs.push(attr),
+                    None => break,
+                }
+            }
+        };
+        visit(&mut FirstAttrs(Some(insert_comments)), node);
+    }
+}
+
+macro_rules! comment_nodes {
+    ($($visit:ident($ty:ty),)*) => {
+        impl VisitMut for Anchors<'_> {
+            $(
+                fn $visit(&mut self, node: &mut $ty) {
+                    self.node(node, |v, node| v.$visit(node));
+                    visit_mut::$visit(self, node);
+                }
+            )*
+        }
+
+        impl VisitMut for Attach {
+            $(
+                fn $visit(&mut self, node: &mut $ty) {
+                    self.node(node, |v, node| v.$visit(node));
+                    visit_mut::$visit(self, node);
+                }
+            )*
+        }
+    };
+}
+
+comment_nodes! {
+    visit_item_mut(Item),
+    visit_impl_item_mut(ImplItem),
+    visit_trait_item_mut(TraitItem),
+    visit_foreign_item_mut(ForeignItem),
+    visit_field_mut(Field),
+    visit_variant_mut(Variant),
+    visit_stmt_mut(Stmt),
+    visit_arm_mut(Arm),
+}
+
+#[test]
+fn test_scan_comments() {
+    let gap = " /* a /* b */ c */\n    // d\r\n  /**/ /* e */\n";
+    assert_eq!(scan_comments(gap), [" a /* b */ c ", " d", "", " e "]);
+    assert_eq!(block_comment_len("/* a /* b */ c */ rest"), 17);
+    // Unterminated, which the tokenizer would have rejected anyway.
+    assert_eq!(block_comment_len("/* a /* b */"), 12);
+}
+
+#[cfg(test)]
+fn restore_str(original: &str, expanded: &str) -> String {
+    let dir = tempfile::tempdir().unwrap();
+    let crate_root = dir.path().join("lib.rs");
+    fs::write(&crate_root, original).unwrap();
+    let mut syntax_tree = syn::parse_file(expanded).unwrap();
+    restore(&mut syntax_tree, &crate_root);
+    crate::unparse::unparse_maximal(&syntax_tree)
+}
+
+#[test]
+fn test_comments_between_attributes() {
+    let original = "// before\n\
+                    #[inline]\n\
+                    // between\n\
+                    #[cold]\n\
+                    // after\n\
+                    fn f() {}\n\
+                    \n\
+                    // on derive\n\
+                    #[derive(Clone)]\n\
+                    // after derive\n\
+                    struct S;\n";
+    let expanded = "#[inline]\n\
+                    #[cold]\n\
+                    fn f() {}\n\
+                    struct S;\n";
+    let expected = "// before\n\
+                    #[inline]\n\
+                    // between\n\
+                    #[cold]\n\
+                    // after\n\
+                    fn f() {}\n\
+                    // on derive\n\
+                    // after derive\n\
+                    struct S;\n";
+    assert_eq!(restore_str(original, expanded), expected);
+}
+
+#[test]
+fn test_comments_in_expanded_item() {
+    let original = "// unchanged\n\
+                    fn f() {\n\
+                    \x20   // kept\n\
+                    \x20   let x = 1;\n\
+                    }\n\
+                    \n\
+                    // changed\n\
+                    fn g() {\n\
+                    \x20   // dropped\n\
+                    \x20   println!(\"x\");\n\
+                    }\n";
+    let expanded = "fn f() {\n\
+                    \x20   let x = 1;\n\
+                    }\n\
+                    fn g() {\n\
+                    \x20   ::std::io::_print(format_args!(\"x\\n\"));\n\
+                    }\n";
+    let expected = "// unchanged\n\
+                    fn f() {\n\
+                    \x20   // kept\n\
+                    \x20   let x = 1;\n\
+                    }\n\
+                    // changed\n\
+                    fn g() {\n\
+                    \x20   ::std::io::_print(format_args!(\"x\\n\"));\n\
+                    }\n";
+    assert_eq!(restore_str(original, expanded), expected);
+}
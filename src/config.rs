@@ -9,7 +9,7 @@ struct Sections {
     expand: Config,
 }
 
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Default, Hash)]
 pub struct Config {
     pub theme: Option<String>,
     pub color: Option<String>,
@@ -20,6 +20,8 @@ pub struct Config {
     /// aesthetic formatting when it works.
     #[serde(default)]
     pub rustfmt: bool,
+    /// Rustup toolchain to expand with, unless overridden by --toolchain.
+    pub toolchain: Option<String>,
 }
 
 pub fn deserialize() -> Config {
@@ -0,0 +1,245 @@
+use crate::error::Result;
+use crate::metadata::{self, Package};
+use crate::opts::Expand;
+use std::io::{self, Write};
+use std::path::Path;
+use tempfile::TempDir;
+use toml::{Table, Value};
+
+// `cargo rustc` only builds workspace members, so expanding a dependency means
+// making it the member of a throwaway workspace, next to a copy of the current
+// lockfile so that its own dependencies resolve to the same versions offline.
+// Returns None if an error was reported.
+pub fn create(args: &mut Expand) -> Result<Option<TempDir>> {
+    let spec = args.dep.take().unwrap_or_default();
+    let metadata = metadata::load_with_dependencies(args)?;
+
+    let candidates: Vec<&Package> = metadata
+        .packages
+        .iter()
+        .filter(|package| package.matches_spec(&spec))
+        .collect();
+    let package = match candidates.as_slice() {
+        [] => {
+            let _ = writeln!(io::stderr(), "ERROR: no dependency matching {}", spec);
+            return Ok(None);
+        }
+        [package] => *package,
+        _ => {
+            let versions: Vec<String> = candidates
+                .iter()
+                .map(|package| format!("{}@{}", package.name, package.version))
+                .collect();
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: {} is ambiguous; specify one of: {}",
+                spec,
+                versions.join(", "),
+            );
+            return Ok(None);
+        }
+    };
+    if metadata.workspace_members.contains(&package.id) {
+        let _ = writeln!(
+            io::stderr(),
+            "ERROR: {} is a workspace member; use -p to expand it",
+            package.name,
+        );
+        return Ok(None);
+    }
+
+    let features = metadata
+        .resolve
+        .iter()
+        .flat_map(|resolve| &resolve.nodes)
+        .find(|node| node.id == package.id)
+        .map_or_else(Vec::new, |node| node.features.clone());
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("cargo-expand-dep");
+    let dir = builder.tempdir()?;
+    let source_dir = package.manifest_path.parent().unwrap();
+    let member_dir = dir.path().join(&package.name);
+    fs_err::create_dir(&member_dir)?;
+    for entry in fs_err::read_dir(source_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name != "Cargo.toml" && name != "target" {
+            link_or_copy(&entry.path(), &member_dir.join(name))?;
+        }
+    }
+    // Cargo resolves the dev-dependencies of workspace members, and those of a
+    // dependency are not in the lockfile or necessarily downloaded.
+    let manifest = fs_err::read_to_string(&package.manifest_path)?;
+    fs_err::write(
+        member_dir.join("Cargo.toml"),
+        without_dev_dependencies(&manifest)?,
+    )?;
+    let manifest_path = dir.path().join("Cargo.toml");
+    fs_err::write(
+        &manifest_path,
+        format!(
+            "[workspace]\nmembers = [{:?}]\nresolver = \"2\"\n",
+            package.name,
+        ),
+    )?;
+    let lockfile = metadata.workspace_root.join("Cargo.lock");
+    if lockfile.exists() {
+        fs_err::copy(lockfile, dir.path().join("Cargo.lock"))?;
+    }
+
+    // The features listed by the resolve include "default" if it is enabled.
+    args.manifest_path = Some(manifest_path);
+    // By path, since the lockfile can have another package of the same name
+    // and version, which the dependency's own dependencies may depend on.
+    args.package = Some(Some(format!(
+        "path+file://{}#{}@{}",
+        url_path(&member_dir),
+        package.name,
+        package.version,
+    )));
+    args.features = vec![features.join(",")];
+    args.all_features = false;
+    args.no_default_features = true;
+    args.offline = true;
+    // As cargo does for packages outside the workspace, which this one would
+    // otherwise become.
+    args.rustc_args.push("--cap-lints=allow".to_owned());
+    if args.target_dir.is_none() {
+        args.target_dir = Some(metadata.target_directory.clone());
+    }
+
+    args.generated = true;
+
+    Ok(Some(dir))
+}
+
+fn without_dev_dependencies(manifest: &str) -> Result<String> {
+    let mut manifest: Table = manifest.parse()?;
+    for key in ["dev-dependencies", "dev_dependencies"] {
+        manifest.remove(key);
+        let targets = manifest.get_mut("target").and_then(Value::as_table_mut);
+        for (_cfg, target) in targets.into_iter().flat_map(|targets| targets.iter_mut()) {
+            if let Some(target) = target.as_table_mut() {
+                target.remove(key);
+            }
+        }
+    }
+    Ok(toml::to_string(&manifest)?)
+}
+
+// The path part of a file URL, which starts with a slash even on Windows.
+fn url_path(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    }
+}
+
+fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::symlink(src, dst).is_ok();
+    #[cfg(windows)]
+    let linked = if src.is_dir() {
+        std::os::windows::fs::symlink_dir(src, dst).is_ok()
+    } else {
+        std::os::windows::fs::symlink_file(src, dst).is_ok()
+    };
+    #[cfg(not(any(unix, windows)))]
+    let linked = false;
+
+    if linked {
+        Ok(())
+    } else if src.is_dir() {
+        copy_dir(src, dst)
+    } else {
+        fs_err::copy(src, dst).map(drop)
+    }
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    fs_err::create_dir_all(dst)?;
+    for entry in fs_err::read_dir(src)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let dst = dst.join(entry.file_name());
+        if file_type.is_dir() {
+            if entry.file_name() != "target" {
+                copy_dir(&entry.path(), &dst)?;
+            }
+        } else {
+            fs_err::copy(entry.path(), dst)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_without_dev_dependencies() {
+    let manifest = r#"
+        [package]
+        name = "dep"
+        version = "1.0.0"
+
+        [dependencies]
+        serde = "1"
+
+        [dev-dependencies]
+        not-downloaded = "1"
+
+        [target.'cfg(unix)'.dependencies]
+        libc = "0.2"
+
+        [target.'cfg(unix)'.dev-dependencies]
+        also-not-downloaded = "1"
+    "#;
+    let manifest: Table = without_dev_dependencies(manifest).unwrap().parse().unwrap();
+    assert!(manifest.contains_key("dependencies"));
+    assert!(!manifest.contains_key("dev-dependencies"));
+    let unix = manifest["target"]["cfg(unix)"].as_table().unwrap();
+    assert!(unix.contains_key("dependencies"));
+    assert!(!unix.contains_key("dev-dependencies"));
+}
+
+// The dependency's dev-dependencies are not in the lockfile, and cannot be
+// fetched offline, but need not be for it to be expanded.
+#[test]
+fn test_dev_dependencies_unavailable() {
+    use clap::Parser as _;
+
+    let dir = tempfile::tempdir().unwrap();
+    let write = |path: &str, content: &str| {
+        let path = dir.path().join(path);
+        fs_err::create_dir_all(path.parent().unwrap()).unwrap();
+        fs_err::write(path, content).unwrap();
+    };
+    write(
+        "app/Cargo.toml",
+        "[package]\nname = \"app\"\nversion = \"0.0.0\"\n\n\
+         [dependencies]\ndep = { path = \"../dep\" }\n",
+    );
+    write("app/src/lib.rs", "");
+    write(
+        "dep/Cargo.toml",
+        "[package]\nname = \"dep\"\nversion = \"1.0.0\"\n\n\
+         [dev-dependencies]\nnot-a-crate-anywhere = \"1\"\n",
+    );
+    write("dep/src/lib.rs", "");
+
+    let manifest_path = dir.path().join("app").join("Cargo.toml");
+    let crate::opts::Subcommand::Expand(mut args) = crate::opts::Subcommand::parse_from([
+        "cargo".as_ref(),
+        "expand".as_ref(),
+        "--offline".as_ref(),
+        "--dep=dep".as_ref(),
+        "--manifest-path".as_ref(),
+        manifest_path.as_os_str(),
+    ]);
+    let workspace = create(&mut args).unwrap().unwrap();
+    let metadata = metadata::load_with_dependencies(&args).unwrap();
+    let package = metadata.selected_package(&args).unwrap();
+    assert_eq!(package.name, "dep");
+    drop(workspace);
+}
@@ -0,0 +1,333 @@
+use crate::comments;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use syn::spanned::Spanned;
+use syn::{parse_quote, Attribute, File, ImplItem, Item, TraitItem};
+
+// An error reported by rustc, as rendered for the terminal. The locations are
+// where it points, and then, if that is inside a macro, where the outermost
+// macro call is.
+pub struct Diagnostic {
+    lines: Vec<String>,
+    locations: Vec<(PathBuf, usize)>,
+}
+
+// A line of cargo's --message-format=json output.
+#[derive(Deserialize)]
+pub struct Message {
+    reason: String,
+    message: Option<CompilerMessage>,
+}
+
+#[derive(Deserialize)]
+struct CompilerMessage {
+    message: String,
+    level: String,
+    spans: Vec<Span>,
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Span {
+    file_name: PathBuf,
+    line_start: usize,
+    is_primary: bool,
+    expansion: Option<Box<Expansion>>,
+}
+
+// The macro call that a span came out of.
+#[derive(Deserialize)]
+struct Expansion {
+    span: Span,
+}
+
+// What rustc says about how we invoke it, as opposed to about the code.
+const NOISE: &[&str] = &[
+    "ignoring specified output filename because multiple outputs were requested",
+    "ignoring specified output filename for 'link' output because multiple outputs were \
+     requested",
+    "ignoring --out-dir flag due to -o flag",
+    "ignoring -C extra-filename flag due to -o flag",
+    "due to multiple output types requested, the explicitly specified output file name \
+     will be adapted for each output type",
+    "warning emitted",
+    "warnings emitted",
+];
+
+impl Message {
+    fn compiler_message(&self) -> Option<&CompilerMessage> {
+        if self.reason == "compiler-message" {
+            self.message.as_ref()
+        } else {
+            None
+        }
+    }
+
+    // Rustc warns that -o is ignored or adapted because of how we invoke it.
+    pub fn is_noise(&self) -> bool {
+        self.compiler_message().is_some_and(|message| {
+            message.level == "warning" && NOISE.iter().any(|noise| message.message.contains(noise))
+        })
+    }
+
+    pub fn rendered(&self) -> Option<&str> {
+        self.compiler_message()?.rendered.as_deref()
+    }
+
+    // An error that points at a location in the source.
+    pub fn error(&self) -> Option<Diagnostic> {
+        let message = self.compiler_message()?;
+        if message.level != "error" {
+            return None;
+        }
+        let span = message.spans.iter().find(|span| span.is_primary)?;
+        let mut locations = vec![(span.file_name.clone(), span.line_start)];
+        let mut call_site = span;
+        while let Some(expansion) = &call_site.expansion {
+            call_site = &expansion.span;
+        }
+        if !std::ptr::eq(call_site, span) {
+            locations.push((call_site.file_name.clone(), call_site.line_start));
+        }
+        let rendered = strip_ansi(message.rendered.as_deref()?);
+        Some(Diagnostic {
+            lines: rendered
+                .trim_end()
+                .lines()
+                .map(|line| line.trim_end().to_owned())
+                .collect(),
+            locations,
+        })
+    }
+}
+
+fn strip_ansi(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\x1b' {
+            if chars.next() == Some('[') {
+                for ch in chars.by_ref() {
+                    if ch.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else {
+            stripped.push(ch);
+        }
+    }
+    stripped
+}
+
+// Show each diagnostic as comments above the expanded item containing the code
+// it points at. The item is found by its path of keys in the original source,
+// and if expansion removed it, the diagnostic goes on its closest surviving
+// ancestor. Code in a macro definition that is not in the output, such as a
+// macro_rules in the same crate, falls back to the macro call, and failing all
+// that the diagnostic goes at the top of the file.
+pub fn attach(syntax_tree: &mut File, crate_root: &Path, diagnostics: &[Diagnostic]) {
+    'diagnostics: for diagnostic in diagnostics {
+        let lines = diagnostic.lines.iter().map(|line| format!(" {}", line));
+        for (file, line) in &diagnostic.locations {
+            let mut keys = Vec::new();
+            let child_dir = crate_root.parent().unwrap_or(Path::new(""));
+            locate_in_file(crate_root, child_dir, file, *line, &mut keys);
+            if let Some(attrs) = find_attrs(&mut syntax_tree.items, &keys) {
+                let comments: Vec<Attribute> =
+                    lines.map(|line| parse_quote!(#[comment = #line])).collect();
+                attrs.splice(0..0, comments);
+                continue 'diagnostics;
+            }
+        }
+        syntax_tree
+            .attrs
+            .extend(lines.map(|line| -> Attribute { parse_quote!(#![comment = #line]) }));
+    }
+}
+
+fn locate_in_file(
+    path: &Path,
+    child_dir: &Path,
+    file: &Path,
+    line: usize,
+    keys: &mut Vec<String>,
+) -> bool {
+    let Ok(content) = fs::read_to_string(path) else {
+        return false;
+    };
+    let Some(syntax) = comments::parse_original(content) else {
+        return false;
+    };
+    let in_file = path.ends_with(file);
+    let file_dir = path.parent().unwrap_or(Path::new(""));
+    locate_in_items(
+        &syntax.items,
+        in_file,
+        file_dir,
+        child_dir,
+        file,
+        line,
+        keys,
+    ) || in_file
+}
+
+fn locate_in_items(
+    items: &[Item],
+    in_file: bool,
+    file_dir: &Path,
+    child_dir: &Path,
+    file: &Path,
+    line: usize,
+    keys: &mut Vec<String>,
+) -> bool {
+    for item in items {
+        let Some(key) = comments::item_key(item) else {
+            continue;
+        };
+        if let Item::Mod(item_mod) = item {
+            let name = item_mod.ident.to_string();
+            let path_attr = comments::path_attr(&item_mod.attrs);
+            keys.push(key);
+            let found = if let Some((_brace, content)) = &item_mod.content {
+                in_file && contains(item, line) && {
+                    let child_dir = child_dir.join(&name);
+                    locate_in_items(content, true, &child_dir, &child_dir, file, line, keys);
+                    true
+                }
+            } else {
+                let (path, grandchild_dir) =
+                    comments::module_file(&name, path_attr, file_dir, child_dir);
+                locate_in_file(&path, &grandchild_dir, file, line, keys)
+            };
+            if found {
+                return true;
+            }
+            keys.pop();
+        } else if in_file && contains(item, line) {
+            keys.push(key);
+            let nested = match item {
+                Item::Impl(item) => item
+                    .items
+                    .iter()
+                    .filter(|item| contains(item, line))
+                    .find_map(comments::impl_item_key),
+                Item::Trait(item) => item
+                    .items
+                    .iter()
+                    .filter(|item| contains(item, line))
+                    .find_map(comments::trait_item_key),
+                _ => None,
+            };
+            keys.extend(nested);
+            return true;
+        }
+    }
+    false
+}
+
+fn contains<T: Spanned>(node: &T, line: usize) -> bool {
+    let span = node.span();
+    span.start().line <= line && line <= span.end().line
+}
+
+// The attributes of the innermost expanded item along the path of keys.
+fn find_attrs<'a>(items: &'a mut [Item], keys: &[String]) -> Option<&'a mut Vec<Attribute>> {
+    let (first, rest) = keys.split_first()?;
+    let item = items
+        .iter_mut()
+        .find(|item| comments::item_key(item).as_ref() == Some(first))?;
+    match item {
+        Item::Mod(item) => match &mut item.content {
+            Some((_brace, content)) if resolves(content, rest) => find_attrs(content, rest),
+            _ => Some(&mut item.attrs),
+        },
+        Item::Impl(item) => {
+            let nested = rest.first().and_then(|key| {
+                item.items
+                    .iter()
+                    .position(|item| comments::impl_item_key(item).as_ref() == Some(key))
+            });
+            match nested.map(|i| &mut item.items[i]) {
+                Some(ImplItem::Const(item)) => Some(&mut item.attrs),
+                Some(ImplItem::Fn(item)) => Some(&mut item.attrs),
+                Some(ImplItem::Type(item)) => Some(&mut item.attrs),
+                _ => Some(&mut item.attrs),
+            }
+        }
+        Item::Trait(item) => {
+            let nested = rest.first().and_then(|key| {
+                item.items
+                    .iter()
+                    .position(|item| comments::trait_item_key(item).as_ref() == Some(key))
+            });
+            match nested.map(|i| &mut item.items[i]) {
+                Some(TraitItem::Const(item)) => Some(&mut item.attrs),
+                Some(TraitItem::Fn(item)) => Some(&mut item.attrs),
+                Some(TraitItem::Type(item)) => Some(&mut item.attrs),
+                _ => Some(&mut item.attrs),
+            }
+        }
+        _ => item_attrs(item),
+    }
+}
+
+fn resolves(items: &[Item], keys: &[String]) -> bool {
+    keys.first().is_some_and(|first| {
+        items
+            .iter()
+            .any(|item| comments::item_key(item).as_ref() == Some(first))
+    })
+}
+
+fn item_attrs(item: &mut Item) -> Option<&mut Vec<Attribute>> {
+    match item {
+        Item::Const(item) => Some(&mut item.attrs),
+        Item::Enum(item) => Some(&mut item.attrs),
+        Item::ExternCrate(item) => Some(&mut item.attrs),
+        Item::Fn(item) => Some(&mut item.attrs),
+        Item::Static(item) => Some(&mut item.attrs),
+        Item::Struct(item) => Some(&mut item.attrs),
+        Item::TraitAlias(item) => Some(&mut item.attrs),
+        Item::Type(item) => Some(&mut item.attrs),
+        Item::Union(item) => Some(&mut item.attrs),
+        Item::Use(item) => Some(&mut item.attrs),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_message() {
+    let noise = r#"{"reason": "compiler-message", "message": {
+        "message": "ignoring -C extra-filename flag due to -o flag",
+        "level": "warning", "spans": [], "rendered": "warning: ignoring"}}"#;
+    let noise: Message = serde_json::from_str(noise).unwrap();
+    assert!(noise.is_noise());
+    assert!(noise.error().is_none());
+
+    let span = |line: usize, expansion: &str| {
+        format!(
+            r#"{{"file_name": "src/lib.rs", "line_start": {}, "is_primary": true,
+                "expansion": {}}}"#,
+            line, expansion,
+        )
+    };
+    let outer = span(10, "null");
+    let inner = span(6, &format!(r#"{{"span": {}}}"#, outer));
+    let error = format!(
+        r#"{{"reason": "compiler-message", "message": {{
+            "message": "nope", "level": "error",
+            "spans": [{}], "rendered": "error: nope"}}}}"#,
+        span(3, &format!(r#"{{"span": {}}}"#, inner)),
+    );
+    let error: Message = serde_json::from_str(&error).unwrap();
+    assert!(!error.is_noise());
+    let diagnostic = error.error().unwrap();
+    assert_eq!(diagnostic.lines, ["error: nope"]);
+    let expected = [
+        (PathBuf::from("src/lib.rs"), 3),
+        (PathBuf::from("src/lib.rs"), 10),
+    ];
+    assert_eq!(diagnostic.locations, expected);
+}
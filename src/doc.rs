@@ -0,0 +1,129 @@
+use std::mem;
+use syn::visit_mut::VisitMut;
+use syn::{Attribute, Expr, ExprLit, File, Lit, LitStr, Meta};
+
+pub fn normalize(syntax_tree: &mut File, strip: bool) {
+    DocComments { strip }.visit_file_mut(syntax_tree);
+}
+
+// - Split multi-line #[doc = "..."] into one attribute per line, so that they
+//   get printed as /// and //! comments instead of /** */ or attributes
+// - Or remove all #[doc = "..."] if stripping docs, keeping #[doc(hidden)]
+struct DocComments {
+    strip: bool,
+}
+
+impl VisitMut for DocComments {
+    fn visit_attributes_mut(&mut self, attrs: &mut Vec<Attribute>) {
+        if self.strip {
+            attrs.retain(|attr| doc_value(attr).is_none());
+        } else if attrs
+            .iter()
+            .any(|attr| doc_value(attr).is_some_and(is_multiline))
+        {
+            *attrs = mem::take(attrs)
+                .into_iter()
+                .flat_map(split_doc_lines)
+                .collect();
+        }
+    }
+}
+
+fn doc_value(attr: &Attribute) -> Option<&LitStr> {
+    if let Meta::NameValue(meta) = &attr.meta {
+        if meta.path.is_ident("doc") {
+            if let Expr::Lit(ExprLit {
+                lit: Lit::Str(lit), ..
+            }) = &meta.value
+            {
+                return Some(lit);
+            }
+        }
+    }
+    None
+}
+
+fn is_multiline(lit: &LitStr) -> bool {
+    lit.value().contains('\n')
+}
+
+fn split_doc_lines(attr: Attribute) -> Vec<Attribute> {
+    let Some(lit) = doc_value(&attr) else {
+        return vec![attr];
+    };
+
+    let value = lit.value();
+    let span = lit.span();
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    value
+        .split('\n')
+        .map(|line| {
+            let mut attr = attr.clone();
+            if let Meta::NameValue(meta) = &mut attr.meta {
+                meta.value = Expr::Lit(ExprLit {
+                    attrs: Vec::new(),
+                    lit: Lit::Str(LitStr::new(line, span)),
+                });
+            }
+            attr
+        })
+        .collect()
+}
+
+#[test]
+fn test_split_doc_lines() {
+    let mut syntax_tree: File = syn::parse_quote! {
+        #![doc = " Crate docs.\n\n More crate docs."]
+        #[doc = " First line.\n Second line.\n"]
+        #[inline]
+        #[doc = " Single line."]
+        fn f() {}
+    };
+    normalize(&mut syntax_tree, false);
+    let expected = "//! Crate docs.\n\
+                    //!\n\
+                    //! More crate docs.\n\
+                    /// First line.\n\
+                    /// Second line.\n\
+                    #[inline]\n\
+                    /// Single line.\n\
+                    fn f() {}\n";
+    assert_eq!(prettyplease::unparse(&syntax_tree), expected);
+}
+
+#[test]
+fn test_strip_docs() {
+    let mut syntax_tree: File = syn::parse_quote! {
+        #![doc = " Crate docs."]
+        #[doc = " Struct docs.\n More."]
+        #[doc(hidden)]
+        pub struct S {
+            #[doc = " Field docs."]
+            pub field: u8,
+        }
+        impl S {
+            #[doc = " Method docs."]
+            #[must_use]
+            pub fn method(&self) -> u8 {
+                self.field
+            }
+        }
+    };
+    normalize(&mut syntax_tree, true);
+    let expected: File = syn::parse_quote! {
+        #[doc(hidden)]
+        pub struct S {
+            pub field: u8,
+        }
+        impl S {
+            #[must_use]
+            pub fn method(&self) -> u8 {
+                self.field
+            }
+        }
+    };
+    assert_eq!(
+        prettyplease::unparse(&syntax_tree),
+        prettyplease::unparse(&expected),
+    );
+}
@@ -0,0 +1,237 @@
+use crate::cmd::CommandExt as _;
+use crate::error::Result;
+use serde::Deserialize;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+pub const CARGO_EXPAND_RUSTDOC_WRAPPER: &str = "CARGO_EXPAND_RUSTDOC_WRAPPER";
+pub const CARGO_EXPAND_DOCTEST_DIR: &str = "CARGO_EXPAND_DOCTEST_DIR";
+pub const CARGO_EXPAND_DOCTEST_UNPRETTY: &str = "CARGO_EXPAND_DOCTEST_UNPRETTY";
+
+pub struct Doctest {
+    pub name: String,
+    pub expanded: String,
+}
+
+// Invoked by `cargo test --doc` in place of rustdoc. Instead of running each
+// doctest, ask rustdoc for the source code it would compile, then have rustc
+// expand that source into $CARGO_EXPAND_DOCTEST_DIR/{crate}/{i}.rs, with the
+// doctest's name alongside in {i}.txt.
+pub fn do_rustdoc_wrapper(rustdoc: &OsStr) -> Result<i32> {
+    let args: Vec<OsString> = env::args_os().skip(1).collect();
+    let outdir = env::var_os(CARGO_EXPAND_DOCTEST_DIR).map(PathBuf::from);
+    let is_test = args.iter().any(|arg| arg == "--test");
+    let Some(outdir) = outdir.filter(|_| is_test) else {
+        let status = Command::new(rustdoc).args(&args).status()?;
+        return Ok(status.code().unwrap_or(1));
+    };
+
+    let mut cmd = Command::new(rustdoc);
+    let mut filters = Vec::new();
+    let mut crate_name = OsString::from("rust_out");
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        if arg == "--test" {
+            continue;
+        }
+        if arg == "--test-run-directory" || arg == "--persist-doctests" {
+            args_iter.next();
+            continue;
+        }
+        if arg == "--test-args" {
+            filters.extend(args_iter.next());
+            continue;
+        }
+        if arg == "--crate-name" {
+            if let Some(name) = args_iter.next() {
+                crate_name.clone_from(name);
+                cmd.arg(arg).arg(name);
+            }
+            continue;
+        }
+        cmd.arg(arg);
+    }
+    cmd.arg("-Zunstable-options");
+    cmd.flag_value("--output-format", "doctest");
+    cmd.env("RUSTC_BOOTSTRAP", "1");
+    cmd.stderr(Stdio::inherit());
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Ok(output.status.code().unwrap_or(1));
+    }
+    let extracted: ExtractedDoctests = serde_json::from_slice(&output.stdout)?;
+
+    let outdir = outdir.join(crate_name);
+    fs_err::create_dir_all(&outdir)?;
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc"));
+    let rustc_args = rustc_args(&args);
+    let crate_edition = edition(&args);
+    let unpretty =
+        env::var_os(CARGO_EXPAND_DOCTEST_UNPRETTY).unwrap_or_else(|| OsString::from("expanded"));
+    let mut arg_z_unpretty = OsString::from(crate::ARG_Z_UNPRETTY);
+    arg_z_unpretty.push(unpretty);
+
+    let mut code = 0;
+    let doctests = extracted.doctests.iter().filter(|doctest| {
+        doctest.doctest_attributes.rust
+            && filters
+                .iter()
+                .all(|filter| doctest.name.contains(&*filter.to_string_lossy()))
+    });
+    for (i, doctest) in doctests.enumerate() {
+        fs_err::write(outdir.join(format!("{}.txt", i)), &doctest.name)?;
+
+        let mut cmd = Command::new(&rustc);
+        cmd.args(&rustc_args);
+        let edition = doctest.doctest_attributes.edition.as_deref();
+        if let Some(edition) = edition.or(crate_edition.as_deref()) {
+            cmd.flag_value("--edition", edition);
+        }
+        cmd.flag_value("--crate-type", "bin");
+        cmd.flag_value("--crate-name", "rust_out");
+        cmd.arg(&arg_z_unpretty);
+        cmd.arg("-o");
+        cmd.arg(outdir.join(format!("{}.rs", i)));
+        cmd.arg("-");
+        cmd.env("RUSTC_BOOTSTRAP", "1");
+        cmd.stdin(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(doctest.source().as_bytes())?;
+        drop(stdin);
+        if !child.wait()?.success() {
+            code = 1;
+        }
+    }
+
+    Ok(code)
+}
+
+// Read back the doctests expanded by the rustdoc wrapper, in order.
+pub fn collect(outdir: &Path) -> Result<Vec<Doctest>> {
+    let mut crate_dirs = Vec::new();
+    for entry in fs_err::read_dir(outdir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            crate_dirs.push(entry.path());
+        }
+    }
+    crate_dirs.sort();
+
+    let mut doctests = Vec::new();
+    for crate_dir in crate_dirs {
+        for i in 0.. {
+            let Ok(name) = fs_err::read_to_string(crate_dir.join(format!("{}.txt", i))) else {
+                break;
+            };
+            let Ok(expanded) = fs_err::read_to_string(crate_dir.join(format!("{}.rs", i))) else {
+                continue;
+            };
+            if !expanded.is_empty() {
+                doctests.push(Doctest { name, expanded });
+            }
+        }
+    }
+    Ok(doctests)
+}
+
+// Rustdoc flags which also determine how rustc compiles each doctest.
+fn rustc_args(rustdoc_args: &[OsString]) -> Vec<OsString> {
+    const FLAGS: &[&str] = &[
+        "--extern",
+        "-L",
+        "--cfg",
+        "--check-cfg",
+        "--target",
+        "--sysroot",
+        "--cap-lints",
+        "--color",
+    ];
+
+    let mut rustc_args = Vec::new();
+    let mut args_iter = rustdoc_args.iter();
+    while let Some(arg) = args_iter.next() {
+        let Some(arg_str) = arg.to_str() else {
+            continue;
+        };
+        if FLAGS.contains(&arg_str) {
+            if let Some(value) = args_iter.next() {
+                rustc_args.push(arg.clone());
+                rustc_args.push(value.clone());
+            }
+        } else if FLAGS.iter().any(|flag| {
+            arg_str
+                .strip_prefix(flag)
+                .is_some_and(|rest| rest.starts_with('='))
+        }) {
+            rustc_args.push(arg.clone());
+        }
+    }
+    rustc_args
+}
+
+fn edition(rustdoc_args: &[OsString]) -> Option<String> {
+    let mut args_iter = rustdoc_args.iter().filter_map(|arg| arg.to_str());
+    while let Some(arg) = args_iter.next() {
+        if arg == "--edition" {
+            return args_iter.next().map(str::to_owned);
+        } else if let Some(edition) = arg.strip_prefix("--edition=") {
+            return Some(edition.to_owned());
+        }
+    }
+    None
+}
+
+#[derive(Deserialize)]
+struct ExtractedDoctests {
+    doctests: Vec<ExtractedDoctest>,
+}
+
+#[derive(Deserialize)]
+struct ExtractedDoctest {
+    name: String,
+    doctest_attributes: DoctestAttributes,
+    original_code: String,
+    doctest_code: Option<DoctestCode>,
+}
+
+#[derive(Deserialize)]
+struct DoctestAttributes {
+    rust: bool,
+    edition: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DoctestCode {
+    crate_level: String,
+    code: String,
+    wrapper: Option<DoctestWrapper>,
+}
+
+#[derive(Deserialize)]
+struct DoctestWrapper {
+    before: String,
+    after: String,
+}
+
+impl ExtractedDoctest {
+    // The complete program rustdoc would compile for this doctest.
+    fn source(&self) -> String {
+        let Some(doctest_code) = &self.doctest_code else {
+            return self.original_code.clone();
+        };
+        let mut source = doctest_code.crate_level.clone();
+        match &doctest_code.wrapper {
+            Some(wrapper) => {
+                source.push_str(&wrapper.before);
+                source.push_str(&doctest_code.code);
+                source.push_str(&wrapper.after);
+            }
+            None => source.push_str(&doctest_code.code),
+        }
+        source
+    }
+}
@@ -1,5 +1,6 @@
+use crate::elide;
 use syn::visit_mut::{self, VisitMut};
-use syn::{Block, File, Item, ItemMod, Stmt};
+use syn::{Block, File, ImplItemFn, Item, ItemFn, ItemMod, Stmt, TraitItemFn};
 
 pub fn sanitize(syntax_tree: &mut File) {
     remove_macro_rules_from_vec_item(&mut syntax_tree.items);
@@ -33,3 +34,52 @@ fn remove_macro_rules_from_vec_item(items: &mut Vec<Item>) {
         _ => true,
     });
 }
+
+pub fn remove_fn_bodies(syntax_tree: &mut File) {
+    RemoveFnBodies.visit_file_mut(syntax_tree);
+}
+
+// Keep the signature of every fn, but replace its body with `{ ... }`
+struct RemoveFnBodies;
+
+impl VisitMut for RemoveFnBodies {
+    fn visit_item_fn_mut(&mut self, i: &mut ItemFn) {
+        elide_block(&mut i.block);
+    }
+
+    fn visit_impl_item_fn_mut(&mut self, i: &mut ImplItemFn) {
+        elide_block(&mut i.block);
+    }
+
+    fn visit_trait_item_fn_mut(&mut self, i: &mut TraitItemFn) {
+        if let Some(block) = &mut i.default {
+            elide_block(block);
+        }
+    }
+}
+
+fn elide_block(block: &mut Block) {
+    block.stmts = vec![Stmt::Expr(elide::ellipsis(), None)];
+}
+
+pub fn collapse_modules(syntax_tree: &mut File, max_depth: usize) {
+    collapse_modules_in_vec_item(&mut syntax_tree.items, 0, max_depth);
+}
+
+// Replace the content of deeply nested modules with a `/* N items */` comment
+fn collapse_modules_in_vec_item(items: &mut [Item], depth: usize, max_depth: usize) {
+    for item in items {
+        if let Item::Mod(ItemMod {
+            content: Some((_, content)),
+            ..
+        }) = item
+        {
+            if depth < max_depth {
+                collapse_modules_in_vec_item(content, depth + 1, max_depth);
+            } else {
+                let unit = if content.len() == 1 { "item" } else { "items" };
+                *content = vec![elide::count_item(content.len(), unit)];
+            }
+        }
+    }
+}
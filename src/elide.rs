@@ -0,0 +1,238 @@
+use proc_macro2::{Ident, Span, TokenStream};
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    token, Attribute, Expr, ExprLit, ExprPath, File, Item, ItemMacro, Lit, Macro, MacroDelimiter,
+    Token,
+};
+
+// Elided code is replaced by a path expression with this prefix, which survives
+// formatting by either prettyplease or rustfmt, and is turned into a comment or
+// `...` afterward.
+const PLACEHOLDER_PREFIX: &str = "Ξelided_";
+
+pub fn elide_literals(syntax_tree: &mut File, max_len: usize) {
+    ElideLiterals { max_len }.visit_file_mut(syntax_tree);
+}
+
+// An expression that renders as `...`.
+pub fn ellipsis() -> Expr {
+    placeholder(PLACEHOLDER_PREFIX.trim_end_matches('_'))
+}
+
+// An item that renders as `/* 37 items */`, on the same line as the braces
+// around it if it is all that is in between.
+pub fn count_item(len: usize, unit: &str) -> Item {
+    let name = format!("{}{}_{}", PLACEHOLDER_PREFIX, len, unit);
+    Item::Macro(ItemMacro {
+        attrs: Vec::new(),
+        ident: None,
+        mac: Macro {
+            path: Ident::new(&name, Span::call_site()).into(),
+            bang_token: Token![!](Span::call_site()),
+            delimiter: MacroDelimiter::Paren(token::Paren::default()),
+            tokens: TokenStream::new(),
+        },
+        semi_token: Some(Token![;](Span::call_site())),
+    })
+}
+
+// Turn `Ξelided_1048576_bytes` into `/* 1048576 bytes */`, and `Ξelided` into
+// `...`.
+pub fn render_placeholders(formatted: &str) -> String {
+    let ellipsis = PLACEHOLDER_PREFIX.trim_end_matches('_');
+    let mut rendered = String::with_capacity(formatted.len());
+    let mut rest = formatted;
+    while let Some(i) = rest.find(ellipsis) {
+        rendered.push_str(&rest[..i]);
+        rest = &rest[i + ellipsis.len()..];
+        let len = rest
+            .find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_')
+            .unwrap_or(rest.len());
+        let suffix = &rest[..len];
+        rest = &rest[len..];
+        match suffix
+            .strip_prefix('_')
+            .and_then(|suffix| suffix.split_once('_'))
+        {
+            Some((count, unit))
+                if !count.is_empty() && count.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                let comment = format!("/* {} {} */", count, unit);
+                if let Some(after_item) = rest.strip_prefix("!();") {
+                    rest = push_alone_in_braces(&mut rendered, after_item, &comment);
+                } else {
+                    rendered.push_str(&comment);
+                }
+            }
+            _ if suffix.is_empty() => rest = push_alone_in_braces(&mut rendered, rest, "..."),
+            // Some other identifier that happens to start the same.
+            _ => {
+                rendered.push_str(ellipsis);
+                rendered.push_str(suffix);
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// A placeholder that is all there is between a pair of braces goes on one line
+// with them, as in `fn f() { ... }`. Returns the rest after the placeholder.
+fn push_alone_in_braces<'a>(rendered: &mut String, rest: &'a str, placeholder: &str) -> &'a str {
+    let line_start = rendered.trim_end_matches(' ').len();
+    if rendered[..line_start].ends_with("{\n") && rest.trim_start().starts_with('}') {
+        rendered.truncate(line_start - 1);
+        rendered.push(' ');
+        rendered.push_str(placeholder);
+        rendered.push(' ');
+        rest.trim_start()
+    } else {
+        rendered.push_str(placeholder);
+        rest
+    }
+}
+
+struct ElideLiterals {
+    max_len: usize,
+}
+
+impl VisitMut for ElideLiterals {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Lit(ExprLit { lit, .. }) => {
+                let (len, unit) = match lit {
+                    Lit::Str(lit) => (lit.value().chars().count(), "chars"),
+                    Lit::ByteStr(lit) => (lit.value().len(), "bytes"),
+                    Lit::CStr(lit) => (lit.value().as_bytes().len(), "bytes"),
+                    _ => return,
+                };
+                if len > self.max_len {
+                    *expr = count_placeholder(len, unit);
+                }
+            }
+            Expr::Array(array) if array.elems.len() > self.max_len => {
+                let len = array.elems.len();
+                array.elems = Punctuated::new();
+                array.elems.push(count_placeholder(len, "elements"));
+            }
+            _ => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_attribute_mut(&mut self, _attr: &mut Attribute) {
+        // Keep doc comments intact.
+    }
+}
+
+fn count_placeholder(len: usize, unit: &str) -> Expr {
+    placeholder(&format!("{}{}_{}", PLACEHOLDER_PREFIX, len, unit))
+}
+
+fn placeholder(name: &str) -> Expr {
+    Expr::Path(ExprPath {
+        attrs: Vec::new(),
+        qself: None,
+        path: Ident::new(name, Span::call_site()).into(),
+    })
+}
+
+#[test]
+fn test_count_placeholder() {
+    let Expr::Path(expr) = count_placeholder(1_048_576, "bytes") else {
+        unreachable!();
+    };
+    let ident = expr.path.get_ident().unwrap();
+    assert_eq!(ident, "Ξelided_1048576_bytes");
+}
+
+#[test]
+fn test_render_placeholders() {
+    let formatted = "static A: &str = Ξelided_300_chars;\n\
+                     static B: [u8; 40] = [Ξelided_40_elements];\n\
+                     fn f() {\n    Ξelided\n}\n\
+                     fn g() -> u8 { Ξelided }\n\
+                     static Ξelided_x_y: u8 = Ξelidedness;\n";
+    let expected = "static A: &str = /* 300 chars */;\n\
+                    static B: [u8; 40] = [/* 40 elements */];\n\
+                    fn f() { ... }\n\
+                    fn g() -> u8 { ... }\n\
+                    static Ξelided_x_y: u8 = Ξelidedness;\n";
+    assert_eq!(render_placeholders(formatted), expected);
+}
+
+#[test]
+fn test_elide_literals() {
+    let mut syntax_tree: File = syn::parse_quote! {
+        const SHORT: &str = "abc";
+        const LONG: &[u8] = b"abcdef";
+        const ARRAY: [u8; 5] = [1, 2, 3, 4, 5];
+        #[doc = "a long doc comment"]
+        fn f() {}
+    };
+    elide_literals(&mut syntax_tree, 4);
+    let rendered = render_placeholders(&prettyplease::unparse(&syntax_tree));
+    let expected = "const SHORT: &str = \"abc\";\n\
+                    const LONG: &[u8] = /* 6 bytes */;\n\
+                    const ARRAY: [u8; 5] = [/* 5 elements */];\n\
+                    ///a long doc comment\n\
+                    fn f() {}\n";
+    assert_eq!(rendered, expected);
+}
+
+#[test]
+fn test_count_item() {
+    let file = File {
+        shebang: None,
+        frontmatter: None,
+        attrs: Vec::new(),
+        items: vec![syn::parse_quote! {
+            mod a {
+                mod b {}
+                const C: u8 = 0;
+            }
+        }],
+    };
+    let mut collapsed = file.clone();
+    let Item::Mod(item) = &mut collapsed.items[0] else {
+        unreachable!();
+    };
+    item.content.as_mut().unwrap().1 = vec![count_item(2, "items")];
+    let rendered = render_placeholders(&prettyplease::unparse(&collapsed));
+    assert_eq!(rendered, "mod a { /* 2 items */ }\n");
+
+    let mut nested = file;
+    let Item::Mod(item) = &mut nested.items[0] else {
+        unreachable!();
+    };
+    let items = &mut item.content.as_mut().unwrap().1;
+    let Item::Mod(inner) = &mut items[0] else {
+        unreachable!();
+    };
+    inner.content.as_mut().unwrap().1 = vec![count_item(1, "item")];
+    items.push(count_item(3, "items"));
+    let rendered = render_placeholders(&prettyplease::unparse(&nested));
+    let expected =
+        "mod a {\n    mod b { /* 1 item */ }\n    const C: u8 = 0;\n    /* 3 items */\n}\n";
+    assert_eq!(rendered, expected);
+}
+
+#[test]
+fn test_ellipsis_body() {
+    let mut file: File = syn::parse_quote! {
+        impl S {
+            fn f(&self) -> u8 {
+                0
+            }
+        }
+    };
+    let Item::Impl(item) = &mut file.items[0] else {
+        unreachable!();
+    };
+    let syn::ImplItem::Fn(method) = &mut item.items[0] else {
+        unreachable!();
+    };
+    method.block.stmts = vec![syn::Stmt::Expr(ellipsis(), None)];
+    let rendered = render_placeholders(&prettyplease::unparse(&file));
+    assert_eq!(rendered, "impl S {\n    fn f(&self) -> u8 { ... }\n}\n");
+}
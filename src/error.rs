@@ -7,9 +7,11 @@ pub enum Error {
     Io(io::Error),
     TomlSer(toml::ser::Error),
     TomlDe(toml::de::Error),
+    Json(serde_json::Error),
     Quote(shlex::QuoteError),
     HomeDir,
     Bat(bat::error::Error),
+    CargoMetadata,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -32,6 +34,12 @@ impl From<toml::de::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
+
 impl From<shlex::QuoteError> for Error {
     fn from(error: shlex::QuoteError) -> Self {
         Error::Quote(error)
@@ -50,9 +58,11 @@ impl Display for Error {
             Error::Io(e) => e.fmt(formatter),
             Error::TomlSer(e) => e.fmt(formatter),
             Error::TomlDe(e) => e.fmt(formatter),
+            Error::Json(e) => e.fmt(formatter),
             Error::Quote(e) => e.fmt(formatter),
             Error::HomeDir => formatter.write_str("could not locate home directory"),
             Error::Bat(e) => e.fmt(formatter),
+            Error::CargoMetadata => formatter.write_str("failed to run `cargo metadata`"),
         }
     }
 }
@@ -63,9 +73,11 @@ impl StdError for Error {
             Error::Io(e) => e.source(),
             Error::TomlSer(e) => e.source(),
             Error::TomlDe(e) => e.source(),
+            Error::Json(e) => e.source(),
             Error::Quote(e) => e.source(),
             Error::HomeDir => None,
             Error::Bat(e) => e.source(),
+            Error::CargoMetadata => None,
         }
     }
 }
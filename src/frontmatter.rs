@@ -0,0 +1,95 @@
+use std::ops::Range;
+
+// Rustc echoes a cargo script's frontmatter verbatim among the inner attributes
+// at the top of the expanded code, where syn would fail to parse it. Returns
+// the frontmatter and the content without it.
+pub fn extract(content: &str) -> Option<(String, String)> {
+    let range = find(content)?;
+    let frontmatter = content[range.clone()].trim_end().to_owned();
+    let rest = content[..range.start].to_owned() + &content[range.end..];
+    Some((frontmatter, rest))
+}
+
+// The frontmatter is the first thing after the shebang and inner attributes,
+// fenced by lines of three or more dashes.
+pub fn find(content: &str) -> Option<Range<usize>> {
+    let mut start = 0;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("---") {
+            let fence = &trimmed[..trimmed.len() - trimmed.trim_start_matches('-').len()];
+            let mut end = start + line.len();
+            for line in content[end..].split_inclusive('\n') {
+                end += line.len();
+                if line.trim() == fence {
+                    return Some(start..end);
+                }
+            }
+            return None;
+        }
+        if !trimmed.is_empty() && !trimmed.starts_with("#!") {
+            return None;
+        }
+        start += line.len();
+    }
+    None
+}
+
+// Put the frontmatter back after the shebang, which is where cargo requires it.
+pub fn insert(formatted: &str, frontmatter: &str) -> String {
+    let shebang_len = if formatted.starts_with("#!") && !formatted.starts_with("#![") {
+        formatted.find('\n').map_or(formatted.len(), |i| i + 1)
+    } else {
+        0
+    };
+    let (shebang, rest) = formatted.split_at(shebang_len);
+    format!("{}{}\n\n{}", shebang, frontmatter, rest)
+}
+
+#[test]
+fn test_find_after_shebang() {
+    let content = "#!/usr/bin/env cargo\n---\n[dependencies]\n---\n\nfn main() {}\n";
+    let range = find(content).unwrap();
+    assert_eq!(&content[range], "---\n[dependencies]\n---\n");
+}
+
+#[test]
+fn test_find_longer_fence() {
+    let content = "----cargo\n[package]\ndescription = \"\"\"\n---\n\"\"\"\n----\nfn main() {}\n";
+    let range = find(content).unwrap();
+    assert_eq!(
+        &content[range],
+        "----cargo\n[package]\ndescription = \"\"\"\n---\n\"\"\"\n----\n",
+    );
+}
+
+#[test]
+fn test_find_unterminated() {
+    assert_eq!(find("---\n[dependencies]\nfn main() {}\n"), None);
+    assert_eq!(find("fn main() {}\n---\n---\n"), None);
+}
+
+#[test]
+fn test_extract_after_inner_attributes() {
+    let content =
+        "#![feature(prelude_import)]\n#![no_std]\n---\n[dependencies]\n---\nfn main() {}\n";
+    let (frontmatter, rest) = extract(content).unwrap();
+    assert_eq!(frontmatter, "---\n[dependencies]\n---");
+    assert_eq!(
+        rest,
+        "#![feature(prelude_import)]\n#![no_std]\nfn main() {}\n"
+    );
+}
+
+#[test]
+fn test_insert() {
+    let frontmatter = "---\n[dependencies]\n---";
+    assert_eq!(
+        insert("#!/usr/bin/env cargo\nfn main() {}\n", frontmatter),
+        "#!/usr/bin/env cargo\n---\n[dependencies]\n---\n\nfn main() {}\n",
+    );
+    assert_eq!(
+        insert("#![allow(dead_code)]\nfn main() {}\n", frontmatter),
+        "---\n[dependencies]\n---\n\n#![allow(dead_code)]\nfn main() {}\n",
+    );
+}
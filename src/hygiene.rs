@@ -0,0 +1,164 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+
+// With -Zunpretty=expanded,hygiene, rustc follows every identifier by its
+// symbol and syntax context: `x /* 2759#4 */`. Identifiers whose name is used
+// in more than one syntax context get the context appended as `x·4`, which
+// remains a valid identifier, and all the annotations are removed. Path
+// segments and field or method names are left alone, since renaming those
+// would refer to something that does not exist.
+pub fn disambiguate(content: &str) -> String {
+    let annotations = annotations(content);
+
+    let mut contexts = HashMap::<&str, BTreeSet<u32>>::new();
+    for annotation in &annotations {
+        if !annotation.qualified {
+            let ident = &content[annotation.ident.clone()];
+            contexts.entry(ident).or_default().insert(annotation.ctxt);
+        }
+    }
+
+    let mut disambiguated = String::with_capacity(content.len());
+    let mut pos = 0;
+    for annotation in &annotations {
+        let ident = &content[annotation.ident.clone()];
+        disambiguated.push_str(&content[pos..annotation.ident.end]);
+        let renamed = !annotation.qualified
+            && contexts[ident].len() > 1
+            && Some(&annotation.ctxt) != contexts[ident].first()
+            && is_renameable(ident);
+        if renamed {
+            disambiguated.push('·');
+            disambiguated.push_str(&annotation.ctxt.to_string());
+        }
+        pos = annotation.end;
+    }
+    disambiguated.push_str(&content[pos..]);
+    disambiguated
+}
+
+struct Annotation {
+    ident: Range<usize>,
+    end: usize,
+    ctxt: u32,
+    // Next to `::`, after `.`, or the name of an attribute.
+    qualified: bool,
+}
+
+// Rustc's pretty printer breaks lines wherever it likes, including around and
+// inside the comment, as in `fn fmt /* 865#6\n    */(&self`.
+fn annotations(content: &str) -> Vec<Annotation> {
+    let mut annotations = Vec::new();
+    let mut pos = 0;
+    while let Some(i) = content[pos..].find("/*") {
+        let comment = pos + i;
+        pos = comment + "/*".len();
+
+        let ident_end = content[..comment].trim_end().len();
+        let rest = &content[pos..];
+        let rest = rest.trim_start();
+        let symbol_len = count_digits(rest);
+        if ident_end == comment || rest.len() == content.len() - pos || symbol_len == 0 {
+            continue;
+        }
+        let Some(rest) = rest[symbol_len..].strip_prefix('#') else {
+            continue;
+        };
+        let ctxt_len = count_digits(rest);
+        let Ok(ctxt) = rest[..ctxt_len].parse::<u32>() else {
+            continue;
+        };
+        let after = rest[ctxt_len..].trim_start();
+        if after.len() == rest.len() - ctxt_len || !after.starts_with("*/") {
+            continue;
+        }
+        let end = content.len() - after.len() + "*/".len();
+
+        let before = &content[..ident_end];
+        let mut ident_start = before
+            .trim_end_matches(|ch: char| ch.is_alphanumeric() || ch == '_')
+            .len();
+        if ident_start == ident_end {
+            continue;
+        }
+        let prefix = &before[..ident_start];
+        if prefix.ends_with("r#") {
+            ident_start -= 2;
+        } else if prefix.ends_with(['\'', '$']) {
+            ident_start -= 1;
+        }
+
+        let prefix = content[..ident_start].trim_end();
+        let qualified = prefix.ends_with("::")
+            || prefix.ends_with('.')
+            || prefix.ends_with("#[")
+            || prefix.ends_with("#![")
+            || content[end..].trim_start().starts_with("::");
+        annotations.push(Annotation {
+            ident: ident_start..ident_end,
+            end,
+            ctxt,
+            qualified,
+        });
+        pos = end;
+    }
+    annotations
+}
+
+fn count_digits(s: &str) -> usize {
+    s.len() - s.trim_start_matches(|ch: char| ch.is_ascii_digit()).len()
+}
+
+// Keywords like `self` cannot be renamed without changing how the code parses.
+fn is_renameable(ident: &str) -> bool {
+    let ident = ident.strip_prefix('\'').unwrap_or(ident);
+    ident.starts_with("r#") || syn::parse_str::<syn::Ident>(ident).is_ok()
+}
+
+#[test]
+fn test_annotations() {
+    let content = "let x /* 1#2 */ = r#x /* 3#0 */ + $crate /* 4#5 */::f /* 6#5 */.0;\n\
+                   fn g /* 7#0\n    */<'a /* 8#1 */>() {}\n\
+                   #[inline /* 9#2 */] y\n    /*\n    10#3\n    */: u8";
+    let found: Vec<(&str, u32, bool)> = annotations(content)
+        .iter()
+        .map(|annotation| {
+            let ident = &content[annotation.ident.clone()];
+            (ident, annotation.ctxt, annotation.qualified)
+        })
+        .collect();
+    assert_eq!(
+        found,
+        [
+            ("x", 2, false),
+            ("r#x", 0, false),
+            ("$crate", 5, true),
+            ("f", 5, true),
+            ("g", 0, false),
+            ("'a", 1, false),
+            ("inline", 2, true),
+            ("y", 3, false),
+        ],
+    );
+}
+
+#[test]
+fn test_disambiguate() {
+    let content = "let x /* 1#0 */ = 1;\n\
+                   let x /* 1#4 */ = S { f: x /* 1#0 */ };\n\
+                   S { f: x /* 1#4 */ };\n\
+                   'a /* 2#0 */: loop { break 'a /* 2#3 */; }\n\
+                   fn fmt /* 5#0 */(f /* 6#0 */: &mut ::core /* 7#4 */::fmt /* 5#4 */::Formatter /* 8#4 */) {}\n\
+                   $crate /* 9#0 */::f /* 6#4 */();\n\
+                   self /* 10#0 */.x /* 1#7 */;\n\
+                   let self /* 10#2 */;";
+    let expected = "let x = 1;\n\
+                    let x·4 = S { f: x };\n\
+                    S { f: x·4 };\n\
+                    'a: loop { break 'a·3; }\n\
+                    fn fmt(f: &mut ::core::fmt::Formatter) {}\n\
+                    $crate::f();\n\
+                    self.x;\n\
+                    let self;";
+    assert_eq!(disambiguate(content), expected);
+}
@@ -0,0 +1,475 @@
+use crate::comments;
+use crate::config::Config;
+use crate::error::Result;
+use crate::metadata::{self, Package, Target};
+use crate::opts::{Coloring, Expand};
+use crate::unparse::unparse_items;
+use crate::workspace;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use syn::{Item, Type};
+
+const EXPAND_COMMAND: &str = "cargo-expand.expandItem";
+const EXPAND_REQUEST: &str = "cargo-expand/expandItem";
+
+// A language server on stdin and stdout. Each item in an open document gets an
+// "Expand item" code lens, which runs EXPAND_COMMAND. Editors without code
+// lenses can send EXPAND_REQUEST with the cursor position instead. Either one
+// responds with {"name": ..., "expansion": ...}.
+pub fn run(
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+) -> Result<i32> {
+    let server = Server {
+        args,
+        config,
+        rustfmt,
+        color,
+    };
+    let mut documents = HashMap::new();
+    let mut stdin = io::stdin().lock();
+    while let Some(message) = read_message(&mut stdin)? {
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                // The id is unknown, so the response has a null one.
+                write_message(&json!({
+                    "jsonrpc": "2.0",
+                    "id": Value::Null,
+                    "error": { "code": -32700, "message": error },
+                }))?;
+                continue;
+            }
+        };
+        let id = message.get("id").cloned();
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "codeLensProvider": { "resolveProvider": false },
+                    "executeCommandProvider": { "commands": [EXPAND_COMMAND] },
+                },
+                "serverInfo": {
+                    "name": "cargo-expand",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "exit" => return Ok(0),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                if let (Some(uri), Some(text)) =
+                    (document["uri"].as_str(), document["text"].as_str())
+                {
+                    documents.insert(uri.to_owned(), text.to_owned());
+                }
+                continue;
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str();
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    documents.insert(uri.to_owned(), text.to_owned());
+                }
+                continue;
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    documents.remove(uri);
+                }
+                continue;
+            }
+            "textDocument/codeLens" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                Ok(code_lenses(uri, &text(&documents, uri)))
+            }
+            "workspace/executeCommand" if params["command"] == EXPAND_COMMAND => {
+                let arguments = &params["arguments"];
+                let uri = arguments[0].as_str().unwrap_or_default();
+                line_number(&arguments[1]["line"])
+                    .and_then(|line| server.expand_item(uri, &text(&documents, uri), line))
+            }
+            EXPAND_REQUEST => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                line_number(&params["position"]["line"])
+                    .and_then(|line| server.expand_item(uri, &text(&documents, uri), line))
+            }
+            _ if id.is_none() => continue,
+            _ => Err((-32601, format!("unsupported method: {}", method))),
+        };
+
+        // Notifications get no response.
+        let Some(id) = id else {
+            continue;
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        write_message(&response)?;
+    }
+    Ok(0)
+}
+
+struct Server<'a> {
+    args: &'a Expand,
+    config: &'a Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+}
+
+type Response = std::result::Result<Value, (i32, String)>;
+
+impl Server<'_> {
+    // Expand the module containing the item on the given line, the same as
+    // `cargo expand path::to::module`, and pick out that item. A type is shown
+    // together with the impls generated for it, which are usually the reason
+    // for looking at its expansion.
+    fn expand_item(&self, uri: &str, text: &str, line: usize) -> Response {
+        let path = uri_to_path(uri).ok_or_else(|| (-32602, format!("not a file: {}", uri)))?;
+        let source = comments::parse_original(text.to_owned())
+            .ok_or_else(|| (-32603, "failed to parse the document".to_owned()))?;
+        // LSP lines are zero-based.
+        let (inline_modules, item) = locate(&source.items, line + 1)
+            .ok_or_else(|| (-32602, "no item at this position".to_owned()))?;
+
+        let metadata = metadata::load(self.args)
+            .map_err(|err| (-32603, format!("cargo metadata: {}", err)))?;
+        let (package, target) = owner(&metadata.packages, &path)
+            .ok_or_else(|| (-32602, format!("{} is not in any target", path.display())))?;
+
+        let mut modules = module_path(&path, &target.src_path).ok_or_else(|| {
+            (
+                -32602,
+                format!("cannot tell the module of {}", path.display()),
+            )
+        })?;
+        modules.extend(inline_modules);
+
+        let mut args = workspace::select(self.args, package, target);
+        args.manifest_path = Some(package.manifest_path.clone());
+        args.item = if modules.is_empty() {
+            None
+        } else {
+            let selector = modules
+                .join("::")
+                .parse()
+                .map_err(|err| (-32603, format!("{}", err)))?;
+            Some(selector)
+        };
+
+        let expanded = crate::expand(&args, self.config, self.rustfmt.clone(), self.color)
+            .map_err(|err| (-32603, err.to_string()))?
+            .0
+            .ok_or_else(|| {
+                (
+                    -32603,
+                    "expansion failed; see the server's stderr".to_owned(),
+                )
+            })?;
+        let mut file = syn::parse_file(&expanded)
+            .map_err(|err| (-32603, format!("unparseable expansion: {}", err)))?;
+        if !modules.is_empty() {
+            match file.items.pop() {
+                Some(Item::Mod(item_mod)) => {
+                    file.items = item_mod
+                        .content
+                        .map(|(_brace, items)| items)
+                        .unwrap_or_default();
+                }
+                _ => return Err((-32603, "expected the selected module".to_owned())),
+            }
+        }
+
+        let key = comments::item_key(&item);
+        let name = type_name(&item);
+        let selected: Vec<Item> = file
+            .items
+            .into_iter()
+            .filter(|expanded| {
+                key.is_some() && comments::item_key(expanded) == key
+                    || name.is_some() && impl_self_name(expanded) == name
+            })
+            .collect();
+        let label = key.unwrap_or_else(|| "macro".to_owned());
+        let expansion = if selected.is_empty() {
+            // A macro call, which is no longer there in the expanded code.
+            expanded
+        } else {
+            unparse_items(selected)
+        };
+        Ok(json!({ "name": label, "expansion": expansion }))
+    }
+}
+
+fn code_lenses(uri: &str, text: &str) -> Value {
+    let mut lenses = Vec::new();
+    if let Some(source) = comments::parse_original(text.to_owned()) {
+        collect_lenses(&source.items, uri, &mut lenses);
+    }
+    Value::Array(lenses)
+}
+
+fn collect_lenses(items: &[Item], uri: &str, lenses: &mut Vec<Value>) {
+    for item in items {
+        if matches!(item, Item::Use(_) | Item::ExternCrate(_)) {
+            continue;
+        }
+        let line = first_line(item) - 1;
+        let position = json!({ "line": line, "character": 0 });
+        lenses.push(json!({
+            "range": { "start": position, "end": position },
+            "command": {
+                "title": "Expand item",
+                "command": EXPAND_COMMAND,
+                "arguments": [uri, position],
+            },
+        }));
+        if let Item::Mod(item) = item {
+            if let Some((_brace, items)) = &item.content {
+                collect_lenses(items, uri, lenses);
+            }
+        }
+    }
+}
+
+// The first line of the item's own tokens, after any attributes and doc
+// comments, which is where editors expect a code lens.
+fn first_line(item: &Item) -> usize {
+    use syn::spanned::Spanned;
+    let ident_line = match item {
+        Item::Fn(item) => Some(item.sig.span()),
+        Item::Struct(item) => Some(item.ident.span()),
+        Item::Enum(item) => Some(item.ident.span()),
+        Item::Union(item) => Some(item.ident.span()),
+        Item::Trait(item) => Some(item.ident.span()),
+        Item::Mod(item) => Some(item.ident.span()),
+        Item::Impl(item) => Some(item.impl_token.span),
+        _ => None,
+    };
+    ident_line.unwrap_or_else(|| item.span()).start().line
+}
+
+// The names of the inline modules that the item on the given line is nested
+// in, and the item itself.
+fn locate(items: &[Item], line: usize) -> Option<(Vec<String>, Item)> {
+    use syn::spanned::Spanned;
+    let item = items.iter().find(|item| {
+        let span = item.span();
+        span.start().line <= line && line <= span.end().line
+    })?;
+    if let Item::Mod(item_mod) = item {
+        if let Some((_brace, content)) = &item_mod.content {
+            if let Some((mut modules, nested)) = locate(content, line) {
+                modules.insert(0, item_mod.ident.to_string());
+                return Some((modules, nested));
+            }
+        }
+    }
+    Some((Vec::new(), item.clone()))
+}
+
+fn type_name(item: &Item) -> Option<String> {
+    match item {
+        Item::Struct(item) => Some(item.ident.to_string()),
+        Item::Enum(item) => Some(item.ident.to_string()),
+        Item::Union(item) => Some(item.ident.to_string()),
+        _ => None,
+    }
+}
+
+fn impl_self_name(item: &Item) -> Option<String> {
+    let Item::Impl(item) = item else {
+        return None;
+    };
+    let Type::Path(self_ty) = &*item.self_ty else {
+        return None;
+    };
+    Some(self_ty.path.segments.last()?.ident.to_string())
+}
+
+// The package and target whose sources contain the file. Files of a package
+// that are not the root of one of its targets belong to its library, if any.
+fn owner<'a>(packages: &'a [Package], file: &Path) -> Option<(&'a Package, &'a Target)> {
+    let package = packages
+        .iter()
+        .filter(|package| file.starts_with(package.manifest_path.parent().unwrap()))
+        .max_by_key(|package| package.manifest_path.as_os_str().len())?;
+    let targets = package
+        .targets
+        .iter()
+        .filter(|target| !target.kind.iter().any(|kind| kind == "custom-build"));
+    let target = targets
+        .clone()
+        .find(|target| target.src_path == file)
+        .or_else(|| package.lib())
+        .or_else(|| {
+            targets
+                .filter(|target| file.starts_with(target.src_path.parent().unwrap()))
+                .max_by_key(|target| target.src_path.as_os_str().len())
+        })?;
+    Some((package, target))
+}
+
+// From the file layout, as in src/a/b.rs or src/a/b/mod.rs being a::b. This
+// does not know about #[path] attributes.
+fn module_path(file: &Path, crate_root: &Path) -> Option<Vec<String>> {
+    if file == crate_root {
+        return Some(Vec::new());
+    }
+    let relative = file.strip_prefix(crate_root.parent()?).ok()?;
+    let mut modules: Vec<String> = relative
+        .with_extension("")
+        .iter()
+        .map(|component| component.to_string_lossy().into_owned())
+        .collect();
+    if modules.last().is_some_and(|last| last == "mod") {
+        modules.pop();
+    }
+    Some(modules)
+}
+
+fn text(documents: &HashMap<String, String>, uri: &str) -> String {
+    match documents.get(uri) {
+        Some(text) => text.clone(),
+        None => uri_to_path(uri)
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default(),
+    }
+}
+
+fn line_number(line: &Value) -> std::result::Result<usize, (i32, String)> {
+    let line = line.as_u64().unwrap_or_default();
+    usize::try_from(line).map_err(|_| (-32602, format!("line out of range: {}", line)))
+}
+
+// A file URI's path is percent-encoded, and on Windows starts with a slash
+// before the drive letter: file:///C:/src/lib.rs.
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?;
+    let encoded = encoded.strip_prefix("localhost").unwrap_or(encoded);
+    let mut decoded = Vec::new();
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    let decoded = String::from_utf8(decoded).ok()?;
+    let bytes = decoded.as_bytes();
+    if bytes.len() >= 3 && bytes[0] == b'/' && bytes[1].is_ascii_alphabetic() && bytes[2] == b':' {
+        return Some(PathBuf::from(&decoded[1..]));
+    }
+    Some(PathBuf::from(decoded))
+}
+
+// Messages are JSON preceded by a Content-Length header and a blank line. A
+// message that cannot be parsed is an Err, after which the next one is read.
+fn read_message(input: &mut impl BufRead) -> Result<Option<std::result::Result<Value, String>>> {
+    let mut content_length = None;
+    let mut malformed = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        match header.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("Content-Length") => {
+                match value.trim().parse::<usize>() {
+                    Ok(length) => content_length = Some(length),
+                    Err(_) => malformed = Some(format!("bad Content-Length: {}", value.trim())),
+                }
+            }
+            Some(_) => {}
+            None => malformed = Some(format!("malformed header: {}", header)),
+        }
+    }
+    if let Some(error) = malformed {
+        return Ok(Some(Err(error)));
+    }
+    let Some(content_length) = content_length else {
+        return Ok(Some(Err("missing Content-Length".to_owned())));
+    };
+    let mut content = vec![0; content_length];
+    input.read_exact(&mut content)?;
+    Ok(Some(
+        serde_json::from_slice(&content).map_err(|err| err.to_string()),
+    ))
+}
+
+fn write_message(message: &Value) -> Result<()> {
+    let content = serde_json::to_string(message)?;
+    let mut stdout = io::stdout().lock();
+    write!(
+        stdout,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )?;
+    stdout.flush()?;
+    Ok(())
+}
+
+#[test]
+fn test_uri_to_path() {
+    assert_eq!(
+        uri_to_path("file:///home/me/src/lib.rs"),
+        Some(PathBuf::from("/home/me/src/lib.rs")),
+    );
+    assert_eq!(
+        uri_to_path("file:///home/me/my%20crate/src/caf%C3%A9.rs"),
+        Some(PathBuf::from("/home/me/my crate/src/café.rs")),
+    );
+    assert_eq!(
+        uri_to_path("file:///C:/src/lib.rs"),
+        Some(PathBuf::from("C:/src/lib.rs")),
+    );
+    assert_eq!(
+        uri_to_path("file:///c%3A/src/lib.rs"),
+        Some(PathBuf::from("c:/src/lib.rs")),
+    );
+    assert_eq!(uri_to_path("untitled:Untitled-1"), None);
+    assert_eq!(uri_to_path("file:///bad%2"), None);
+}
+
+#[test]
+fn test_read_message() {
+    let mut input = io::Cursor::new(
+        "Content-Length: 2\r\n\r\n{}\
+         Content-Length: x\r\n\r\n\
+         Content-Length: 3\r\n\r\n{]}\
+         Content-Type: application/vscode-jsonrpc\r\nContent-Length: 4\r\n\r\nnull",
+    );
+    assert_eq!(read_message(&mut input).unwrap(), Some(Ok(json!({}))));
+    assert!(matches!(read_message(&mut input).unwrap(), Some(Err(_))));
+    assert!(matches!(read_message(&mut input).unwrap(), Some(Err(_))));
+    assert_eq!(read_message(&mut input).unwrap(), Some(Ok(Value::Null)));
+    assert_eq!(read_message(&mut input).unwrap(), None);
+}
+
+#[test]
+fn test_line_number() {
+    assert_eq!(line_number(&json!(7)), Ok(7));
+    assert_eq!(line_number(&Value::Null), Ok(0));
+}
@@ -16,35 +16,59 @@
     clippy::uninlined_format_args,
 )]
 
+mod api;
 mod assets;
+mod build_script;
+mod cache;
 mod cmd;
+mod comments;
 mod config;
+mod dependency;
+mod diagnostics;
+mod doc;
+mod doctest;
 mod edit;
+mod elide;
 mod error;
 mod fmt;
+mod frontmatter;
+mod hygiene;
+mod lsp;
 mod manifest;
+mod matrix;
+mod metadata;
 mod opts;
+mod snippet;
+mod toolchain;
+mod tui;
 mod unparse;
 mod version;
+mod watch;
+mod workspace;
 
+use crate::cache::{BootstrapKey, ExpansionKey};
 use crate::cmd::CommandExt as _;
 use crate::config::Config;
+use crate::diagnostics::Diagnostic;
 use crate::error::Result;
-use crate::opts::{Coloring, Expand, Subcommand};
+use crate::opts::{Coloring, Expand, MessageFormat, Subcommand};
 use crate::unparse::unparse_maximal;
 use crate::version::Version;
 use bat::assets::HighlightingAssets;
 use bat::assets_metadata::AssetsMetadata;
 use bat::config::VisibleLines;
 use bat::line_range::{HighlightedLineRanges, LineRanges};
+use bat::output::OutputHandle;
 use bat::style::StyleComponents;
 use bat::theme::{ThemeName, ThemeOptions, ThemePreference};
 use bat::{PagingMode, SyntaxMapping, WrappingMode};
-use clap::{CommandFactory as _, Parser, ValueEnum};
+use clap::{CommandFactory as _, ValueEnum};
 use quote::quote;
+use serde::Serialize;
 use std::env;
 use std::error::Error as StdError;
 use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
 use std::io::{self, BufRead, IsTerminal, Write};
 use std::iter;
 use std::panic::PanicHookInfo;
@@ -53,7 +77,7 @@ use std::path::{Path, PathBuf};
 use std::process::{self, Command, Stdio};
 use std::ptr;
 use std::str;
-use std::thread::Result as ThreadResult;
+use std::thread::{self, Result as ThreadResult};
 use termcolor::{Color::Green, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 cargo_subcommand_metadata::description!("Show result of macro expansion");
@@ -61,6 +85,8 @@ cargo_subcommand_metadata::description!("Show result of macro expansion");
 fn main() {
     let result = if let Some(wrapper) = env::var_os(CARGO_EXPAND_RUSTC_WRAPPER) {
         do_rustc_wrapper(&wrapper)
+    } else if let Some(rustdoc) = env::var_os(doctest::CARGO_EXPAND_RUSTDOC_WRAPPER) {
+        doctest::do_rustdoc_wrapper(&rustdoc)
     } else {
         do_cargo_expand()
     };
@@ -81,7 +107,7 @@ fn main() {
 }
 
 const CARGO_EXPAND_RUSTC_WRAPPER: &str = "CARGO_EXPAND_RUSTC_WRAPPER";
-const ARG_Z_UNPRETTY_EXPANDED: &str = "-Zunpretty=expanded";
+const ARG_Z_UNPRETTY: &str = "-Zunpretty=";
 
 fn cargo_binary() -> OsString {
     env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"))
@@ -98,16 +124,22 @@ fn do_rustc_wrapper(wrapper: &OsStr) -> Result<i32> {
         return Ok(1);
     };
 
-    let mut is_unpretty_expanded = false;
+    let mut is_unpretty = false;
     for arg in rustc_command {
-        is_unpretty_expanded |= arg == ARG_Z_UNPRETTY_EXPANDED;
+        is_unpretty |= arg
+            .to_str()
+            .is_some_and(|arg| arg.starts_with(ARG_Z_UNPRETTY));
         cmd.arg(arg);
     }
 
-    if is_unpretty_expanded {
+    if is_unpretty {
         cmd.env("RUSTC_BOOTSTRAP", "1");
     }
 
+    if let Some(outfile) = env::var_os(build_script::CARGO_EXPAND_BUILD_SCRIPT) {
+        build_script::expand(&cmd, &outfile);
+    }
+
     #[cfg(unix)]
     {
         use crate::error::Error;
@@ -126,7 +158,7 @@ fn do_rustc_wrapper(wrapper: &OsStr) -> Result<i32> {
 }
 
 fn do_cargo_expand() -> Result<i32> {
-    let Subcommand::Expand(args) = Subcommand::parse();
+    let mut args = Subcommand::parse_expand();
 
     if args.version {
         let version = Version {
@@ -143,6 +175,27 @@ fn do_cargo_expand() -> Result<i32> {
         return Ok(0);
     }
 
+    if let Some(toolchain) = args.toolchain.as_ref().or(config.toolchain.as_ref()) {
+        if !toolchain::select(toolchain) {
+            return Ok(1);
+        }
+    }
+
+    // Some modes expand a package generated in a temporary directory, which
+    // needs to stay around until expansion is done.
+    let generated = if args.snippet.is_some() {
+        Some(snippet::create(&mut args)?)
+    } else if args.dep.is_some() {
+        Some(dependency::create(&mut args)?)
+    } else {
+        None
+    };
+    if let Some(None) = generated {
+        return Ok(1);
+    }
+
+    let unpretty = args.unpretty();
+    let parseable = args.hygiene || unpretty.is_parseable();
     if let Some(item) = &args.item {
         if args.ugly {
             let _ = writeln!(
@@ -152,6 +205,15 @@ fn do_cargo_expand() -> Result<i32> {
             );
             return Ok(1);
         }
+        if !parseable {
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: cannot expand single item ({}) with --unpretty={}.",
+                item,
+                unpretty.name(),
+            );
+            return Ok(1);
+        }
     }
 
     let mut rustfmt = None;
@@ -167,128 +229,367 @@ fn do_cargo_expand() -> Result<i32> {
         }
     }
 
+    let color = get_color(&args, &config);
+
+    if args.tui && !io::stdout().is_terminal() {
+        let _ = writeln!(io::stderr(), "ERROR: --tui requires a terminal");
+        return Ok(1);
+    }
+
+    if args.watch {
+        return watch::run(&args, config, rustfmt, color);
+    }
+
+    if args.lsp {
+        return lsp::run(&args, &config, rustfmt, color);
+    }
+
+    expand_and_print(&args, &config, rustfmt, color)
+}
+
+fn expand_and_print(
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+) -> Result<i32> {
+    if !args.targets.is_empty() {
+        return matrix::expand_targets(args, config, rustfmt, color);
+    }
+
+    if args.feature_matrix.is_some() {
+        return matrix::expand_features(args, config, rustfmt, color);
+    }
+
+    if args.workspace || args.all_targets {
+        return workspace::expand_each(args, config, rustfmt, color);
+    }
+
+    let (content, code) = expand(args, config, rustfmt, color)?;
+    if let Some(content) = content {
+        print_expanded(&content, args, config, color)?;
+    }
+    Ok(code)
+}
+
+// Expands the target selected by args. Returns the expanded code, unless
+// expansion failed after reporting errors, along with the exit code.
+fn expand(
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+) -> Result<(Option<String>, i32)> {
+    let unpretty = args.unpretty();
+    let parseable = args.hygiene || unpretty.is_parseable();
+
     let mut builder = tempfile::Builder::new();
     builder.prefix("cargo-expand");
     let outdir = builder.tempdir().expect("failed to create tmp file");
     let outfile_path = outdir.path().join("expanded");
-    let color = get_color(&args, &config);
+
+    if args.build_script && !build_script::clean(args)? {
+        return Ok((None, 1));
+    }
 
     // Run cargo
     let mut cmd = Command::new(cargo_binary());
-    apply_args(&mut cmd, &args, color, &outfile_path);
+    apply_args(&mut cmd, args, color, &outfile_path);
+
+    let cache_key = if args.doc.is_none() && !args.build_script {
+        ExpansionKey::new(args, config, &cmd, &outfile_path, rustfmt.as_deref())
+    } else {
+        None
+    };
+    if let Some(cached) = cache_key.as_ref().and_then(ExpansionKey::load) {
+        return Ok((Some(cached), 0));
+    }
+
     if args.verbose {
         print_command(&cmd, color)?;
     }
 
-    if needs_rustc_bootstrap() {
+    if args.doc.is_some() {
+        if let Ok(current_exe) = env::current_exe() {
+            let original_rustdoc = env::var_os("RUSTDOC").filter(|rustdoc| !rustdoc.is_empty());
+            let rustdoc = original_rustdoc.unwrap_or_else(|| OsString::from("rustdoc"));
+            cmd.env(doctest::CARGO_EXPAND_RUSTDOC_WRAPPER, rustdoc);
+            cmd.env(doctest::CARGO_EXPAND_DOCTEST_DIR, outdir.path());
+            cmd.env(doctest::CARGO_EXPAND_DOCTEST_UNPRETTY, unpretty.name());
+            cmd.env("RUSTDOC", current_exe);
+        }
+    } else if args.build_script || needs_rustc_bootstrap(args) {
         if let Ok(current_exe) = env::current_exe() {
             let original_wrapper =
                 env::var_os("RUSTC_WRAPPER").filter(|wrapper| !wrapper.is_empty());
             let wrapper = original_wrapper.as_deref().unwrap_or(OsStr::new("/"));
             cmd.env(CARGO_EXPAND_RUSTC_WRAPPER, wrapper);
             cmd.env("RUSTC_WRAPPER", current_exe);
+            if args.build_script {
+                cmd.env(build_script::CARGO_EXPAND_BUILD_SCRIPT, &outfile_path);
+                cmd.env(
+                    build_script::CARGO_EXPAND_BUILD_SCRIPT_UNPRETTY,
+                    unpretty.name(),
+                );
+            }
         } else {
             cmd.env("RUSTC_BOOTSTRAP", "1");
         }
     }
 
-    let code = filter_err(&mut cmd)?;
+    let (code, diagnostics) = filter_err(&mut cmd, args)?;
+
+    if args.doc.is_some() {
+        let doctests = doctest::collect(outdir.path())?;
+        if doctests.is_empty() {
+            let _ = writeln!(io::stderr(), "ERROR: no doctests were expanded");
+            return Ok((None, if code == 0 { 1 } else { code }));
+        }
+        let mut content = String::new();
+        for doctest in doctests {
+            let mut expanded = doctest.expanded;
+            if args.hygiene {
+                expanded = hygiene::disambiguate(&expanded);
+            }
+            if !args.ugly && parseable {
+                let rustfmt = rustfmt.clone();
+                match format_expanded(expanded, args, config, rustfmt, outdir.path(), &[])? {
+                    Some(formatted) => expanded = formatted,
+                    None => return Ok((None, 1)),
+                }
+            }
+            if !content.is_empty() {
+                content.push('\n');
+            }
+            let _ = writeln!(content, "// ==== {} ====", doctest.name);
+            content.push_str(&expanded);
+        }
+        return Ok((Some(content), code));
+    }
 
     if !outfile_path.exists() {
-        return Ok(1);
+        return Ok((None, 1));
     }
 
     let mut content = fs_err::read_to_string(&outfile_path)?;
     if content.is_empty() {
         let _ = writeln!(io::stderr(), "ERROR: rustc produced no expanded output");
-        return Ok(if code == 0 { 1 } else { code });
+        return Ok((None, if code == 0 { 1 } else { code }));
+    }
+
+    if args.hygiene {
+        content = hygiene::disambiguate(&content);
     }
 
     // Format the expanded code
-    if !args.ugly {
-        let questionably_formatted = content;
-
-        // Work around rustfmt not being able to parse paths containing $crate.
-        // This placeholder should be the same width as $crate to preserve
-        // alignments.
-        const DOLLAR_CRATE_PLACEHOLDER: &str = "Ξcrate";
-        let wip = questionably_formatted.replace("$crate", DOLLAR_CRATE_PLACEHOLDER);
-
-        enum Stage {
-            Formatted(String),
-            Unformatted(String),
-            QuestionablyFormatted,
-        }
-
-        let mut stage = Stage::QuestionablyFormatted;
-
-        // Discard comments, which are misplaced by the compiler
-        if let Ok(mut syntax_tree) = syn::parse_file(&wip) {
-            edit::sanitize(&mut syntax_tree);
-            if let Some(filter) = args.item {
-                syntax_tree.shebang = None;
-                syntax_tree.attrs.clear();
-                syntax_tree.items = filter.apply_to(&syntax_tree);
-                if syntax_tree.items.is_empty() {
-                    let _ = writeln!(io::stderr(), "WARNING: no such item: {}", filter);
-                    return Ok(1);
-                }
+    if !args.ugly && parseable {
+        let mut frontmatter = None;
+        if manifest::is_script(args.manifest_path.as_deref()) {
+            if let Some((extracted, rest)) = frontmatter::extract(&content) {
+                frontmatter = Some(extracted);
+                content = rest;
             }
-            if !config.rustfmt {
-                if let Ok(formatted) = ignore_panic(|| unparse_maximal(&syntax_tree)) {
-                    stage = Stage::Formatted(formatted);
+        }
+        // Rustc writes out what it managed to expand even if expansion
+        // reported errors, so show the errors next to the code they are about.
+        match format_expanded(content, args, config, rustfmt, outdir.path(), &diagnostics)? {
+            Some(formatted) => content = formatted,
+            None => return Ok((None, 1)),
+        }
+        if let Some(frontmatter) = frontmatter.filter(|_| args.item.is_none()) {
+            content = frontmatter::insert(&content, &frontmatter);
+        }
+    }
+
+    if code == 0 {
+        if let Some(cache_key) = cache_key {
+            cache_key.store(&content);
+        }
+    }
+    Ok((Some(content), code))
+}
+
+// Returns None if the requested item could not be selected.
+fn format_expanded(
+    content: String,
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<PathBuf>,
+    outdir: &Path,
+    diagnostics: &[Diagnostic],
+) -> Result<Option<String>> {
+    let questionably_formatted = content;
+
+    // Work around rustfmt not being able to parse paths containing $crate.
+    // This placeholder should be the same width as $crate to preserve
+    // alignments.
+    const DOLLAR_CRATE_PLACEHOLDER: &str = "Ξcrate";
+    let wip = questionably_formatted.replace("$crate", DOLLAR_CRATE_PLACEHOLDER);
+
+    enum Stage {
+        Formatted(String),
+        Unformatted(String),
+        QuestionablyFormatted,
+    }
+
+    let mut stage = Stage::QuestionablyFormatted;
+
+    // Discard comments, which are misplaced by the compiler
+    if let Ok(mut syntax_tree) = syn::parse_file(&wip) {
+        if args.comments {
+            match comments::crate_root(args) {
+                Some(crate_root) => comments::restore(&mut syntax_tree, &crate_root),
+                None => {
+                    let _ = writeln!(
+                        io::stderr(),
+                        "WARNING: unable to locate source files to restore comments from",
+                    );
                 }
             }
-            if let Stage::QuestionablyFormatted = stage {
-                let unformatted = quote!(#syntax_tree).to_string();
-                stage = Stage::Unformatted(unformatted);
+        }
+        if !diagnostics.is_empty() {
+            if let Some(crate_root) = comments::crate_root(args) {
+                diagnostics::attach(&mut syntax_tree, &crate_root, diagnostics);
+            }
+        }
+        edit::sanitize(&mut syntax_tree);
+        doc::normalize(&mut syntax_tree, args.strip_docs);
+        if args.public_api {
+            api::retain_public_api(&mut syntax_tree);
+        }
+        if args.signatures {
+            edit::remove_fn_bodies(&mut syntax_tree);
+        }
+        if let Some(max_len) = args.elide_literals {
+            elide::elide_literals(&mut syntax_tree, max_len);
+        }
+        if let Some(filter) = &args.item {
+            syntax_tree.shebang = None;
+            syntax_tree.attrs.clear();
+            syntax_tree.items = filter.apply_to(&syntax_tree);
+            if syntax_tree.items.is_empty() {
+                let _ = writeln!(io::stderr(), "WARNING: no such item: {}", filter);
+                return Ok(None);
+            }
+        }
+        if let Some(depth) = args.depth {
+            edit::collapse_modules(&mut syntax_tree, depth);
+        }
+        if !config.rustfmt {
+            if let Ok(formatted) = ignore_panic(|| unparse_maximal(&syntax_tree)) {
+                stage = Stage::Formatted(formatted);
+            }
+        }
+        if let Stage::QuestionablyFormatted = stage {
+            let mut unformatted = quote!(#syntax_tree).to_string();
+            if let Some(shebang) = &syntax_tree.shebang {
+                unformatted = format!("{}\n{}", shebang, unformatted);
             }
+            stage = Stage::Unformatted(unformatted);
         }
+    } else if let Some(item) = &args.item {
+        let _ = writeln!(
+            io::stderr(),
+            "ERROR: cannot expand single item ({}) because rustc's output could not be parsed.",
+            item,
+        );
+        return Ok(None);
+    }
 
-        let to_rustfmt = match &stage {
-            Stage::Formatted(_) => None,
-            Stage::Unformatted(unformatted) => Some(unformatted),
-            Stage::QuestionablyFormatted => Some(&wip),
-        };
+    let to_rustfmt = match &stage {
+        Stage::Formatted(_) => None,
+        Stage::Unformatted(unformatted) => Some(unformatted),
+        Stage::QuestionablyFormatted => Some(&wip),
+    };
 
-        if let Some(unformatted) = to_rustfmt {
-            if let Some(rustfmt) = rustfmt.or_else(which_rustfmt) {
-                fs_err::write(&outfile_path, unformatted)?;
-
-                fmt::write_rustfmt_config(&outdir)?;
-
-                for edition in &["2021", "2018", "2015"] {
-                    let output = Command::new(&rustfmt)
-                        .flag_value("--edition", edition)
-                        .arg(&outfile_path)
-                        .stderr(Stdio::null())
-                        .output();
-                    if let Ok(output) = output {
-                        if output.status.success() {
-                            stage = Stage::Formatted(fs_err::read_to_string(&outfile_path)?);
-                            break;
-                        }
+    if let Some(unformatted) = to_rustfmt {
+        if let Some(rustfmt) = rustfmt.or_else(which_rustfmt) {
+            let outfile_path = outdir.join("expanded");
+            fs_err::write(&outfile_path, unformatted)?;
+
+            fmt::write_rustfmt_config(outdir)?;
+
+            for edition in &["2021", "2018", "2015"] {
+                let output = Command::new(&rustfmt)
+                    .flag_value("--edition", edition)
+                    .arg(&outfile_path)
+                    .stderr(Stdio::null())
+                    .output();
+                if let Ok(output) = output {
+                    if output.status.success() {
+                        let formatted = fs_err::read_to_string(&outfile_path)?;
+                        let formatted = comments::render_rustfmt_output(&formatted);
+                        stage = Stage::Formatted(formatted);
+                        break;
                     }
                 }
             }
         }
+    }
+
+    let content = match stage {
+        Stage::Formatted(formatted) => {
+            let formatted = formatted.replace(DOLLAR_CRATE_PLACEHOLDER, "$crate");
+            elide::render_placeholders(&formatted)
+        }
+        Stage::Unformatted(_) | Stage::QuestionablyFormatted => questionably_formatted,
+    };
+
+    Ok(Some(content))
+}
+
+#[derive(Serialize)]
+struct ExpansionMessage<'a> {
+    reason: &'static str,
+    expanded: &'a str,
+}
 
-        content = match stage {
-            Stage::Formatted(formatted) => formatted.replace(DOLLAR_CRATE_PLACEHOLDER, "$crate"),
-            Stage::Unformatted(_) | Stage::QuestionablyFormatted => questionably_formatted,
+fn print_expanded(content: &str, args: &Expand, config: &Config, color: Coloring) -> Result<()> {
+    if args.message_format == Some(MessageFormat::Json) {
+        // One more message after the compiler's, in the same stream.
+        let message = ExpansionMessage {
+            reason: "expansion",
+            expanded: content,
         };
+        let _ = writeln!(io::stdout(), "{}", serde_json::to_string(&message)?);
+        return Ok(());
+    }
+
+    if args.tui {
+        return tui::browse(content, args, config, color);
     }
 
     // Run pretty printer
-    let mut theme = args.theme.or(config.theme);
-    let none_theme = theme.as_deref() == Some("none");
-    let do_color = match color {
-        Coloring::Always => true,
-        Coloring::Never => false,
-        Coloring::Auto => !none_theme && io::stdout().is_terminal(),
-    };
     let _ = writeln!(io::stderr());
-    if do_color {
+    match Highlighter::new(args, config, color)? {
+        Some(highlighter) => highlighter.print(content),
+        None => {
+            let _ = write!(io::stdout(), "{}", content);
+        }
+    }
+
+    Ok(())
+}
+
+struct Highlighter {
+    config: bat::config::Config<'static>,
+    assets: HighlightingAssets,
+}
+
+impl Highlighter {
+    // None if the output is not going to be colored.
+    fn new(args: &Expand, config: &Config, color: Coloring) -> Result<Option<Self>> {
+        let mut theme = args.theme.clone().or_else(|| config.theme.clone());
+        let none_theme = theme.as_deref() == Some("none");
+        let do_color = match color {
+            Coloring::Always => true,
+            Coloring::Never => false,
+            Coloring::Auto => !none_theme && io::stdout().is_terminal(),
+        };
+        if !do_color {
+            return Ok(None);
+        }
+
         let theme_result = bat::theme::theme(ThemeOptions {
             theme: theme
                 .clone()
@@ -325,7 +626,7 @@ fn do_cargo_expand() -> Result<i32> {
             }
         }
         let config = bat::config::Config {
-            language: Some("rust"),
+            language: args.unpretty().is_rust_like().then_some("rust"),
             show_nonprintable: false,
             term_width: console::Term::stdout().size().1 as usize,
             tab_width: 4,
@@ -339,22 +640,32 @@ fn do_cargo_expand() -> Result<i32> {
                 PagingMode::Never
             },
             visible_lines: VisibleLines::Ranges(LineRanges::all()),
-            theme: theme.unwrap_or_else(String::new),
+            theme: theme.unwrap_or_default(),
             syntax_mapping: SyntaxMapping::new(),
             pager: None,
             use_italic_text: false,
             highlighted_lines: HighlightedLineRanges(LineRanges::none()),
             ..Default::default()
         };
-        let controller = bat::controller::Controller::new(&config, &assets);
+        Ok(Some(Highlighter { config, assets }))
+    }
+
+    fn print(&self, content: &str) {
+        let controller = bat::controller::Controller::new(&self.config, &self.assets);
         let inputs = vec![bat::input::Input::from_reader(Box::new(content.as_bytes()))];
         // Ignore any errors.
         let _ = controller.run(inputs, None);
-    } else {
-        let _ = write!(io::stdout(), "{}", content);
     }
 
-    Ok(0)
+    // Highlighted with terminal escape sequences, one line at a time.
+    fn highlight(&self, content: &str) -> String {
+        let mut highlighted = String::new();
+        let controller = bat::controller::Controller::new(&self.config, &self.assets);
+        let inputs = vec![bat::input::Input::from_reader(Box::new(content.as_bytes()))];
+        let mut output = OutputHandle::FmtWrite(&mut highlighted);
+        let _ = controller.run(inputs, Some(&mut output));
+        highlighted
+    }
 }
 
 fn which_rustfmt() -> Option<PathBuf> {
@@ -371,7 +682,14 @@ fn which_rustfmt() -> Option<PathBuf> {
 }
 
 fn apply_args(cmd: &mut Command, args: &Expand, color: Coloring, outfile: &Path) {
-    cmd.arg("rustc");
+    if args.doc.is_some() {
+        cmd.arg("test");
+        cmd.arg("--doc");
+    } else if args.build_script {
+        cmd.arg("check");
+    } else {
+        cmd.arg("rustc");
+    }
 
     if args.verbose {
         cmd.arg("--verbose");
@@ -381,18 +699,20 @@ fn apply_args(cmd: &mut Command, args: &Expand, color: Coloring, outfile: &Path)
         cmd.arg("--quiet");
     }
 
-    match color {
-        Coloring::Auto => {
-            if cfg!(not(windows)) && io::stderr().is_terminal() {
-                cmd.flag_value("--color", "always");
-            } else {
-                cmd.flag_value("--color", "never");
-            }
-        }
-        color => {
-            cmd.flag_value("--color", color.to_possible_value().unwrap().get_name());
-        }
-    }
+    let stderr_color = match color {
+        Coloring::Auto => cfg!(not(windows)) && io::stderr().is_terminal(),
+        Coloring::Always => true,
+        Coloring::Never => false,
+    };
+    cmd.flag_value("--color", if stderr_color { "always" } else { "never" });
+
+    // Compiler messages are rendered by filter_err.
+    let message_format = if stderr_color && args.message_format != Some(MessageFormat::Json) {
+        "json-diagnostic-rendered-ansi"
+    } else {
+        "json"
+    };
+    cmd.flag_value("--message-format", message_format);
 
     for kv in &args.config {
         cmd.flag_value("--config", kv);
@@ -402,6 +722,11 @@ fn apply_args(cmd: &mut Command, args: &Expand, color: Coloring, outfile: &Path)
         cmd.arg(format!("-Z{}", unstable_flag));
     }
 
+    if manifest::is_script(args.manifest_path.as_deref()) {
+        cmd.arg("-Zscript");
+        cmd.env("RUSTC_BOOTSTRAP", "1");
+    }
+
     if let Some(opt_package) = &args.package {
         if let Some(package) = opt_package {
             cmd.flag_value("--package", package);
@@ -452,7 +777,7 @@ fn apply_args(cmd: &mut Command, args: &Expand, color: Coloring, outfile: &Path)
         has_explicit_build_target = true;
     }
 
-    if !has_explicit_build_target {
+    if !has_explicit_build_target && args.doc.is_none() && !args.build_script {
         if let Ok(cargo_manifest) = manifest::parse(args.manifest_path.as_deref()) {
             if let Some(root_package) = cargo_manifest.package {
                 if let Some(default_run) = &root_package.default_run {
@@ -482,8 +807,16 @@ fn apply_args(cmd: &mut Command, args: &Expand, color: Coloring, outfile: &Path)
         cmd.arg("--keep-going");
     }
 
-    if let Some(profile) = &args.profile {
+    if args.build_script {
+        cmd.flag_value("--profile", build_script::PROFILE);
+        cmd.flag_value("--config", build_script::profile_config(args));
+    } else if let Some(profile) = &args.profile {
         cmd.flag_value("--profile", profile);
+    } else if args.doc.is_some() {
+        // Doctests link against the real build of the library.
+        if args.release {
+            cmd.arg("--release");
+        }
     } else if args.tests && args.test.is_none() {
         if args.release {
             cmd.flag_value("--profile", "bench");
@@ -524,18 +857,66 @@ fn apply_args(cmd: &mut Command, args: &Expand, color: Coloring, outfile: &Path)
         cmd.arg("--frozen");
     }
 
+    if let Some(opt_filter) = &args.doc {
+        // Passed on to rustdoc as --test-args.
+        if let Some(filter) = opt_filter {
+            cmd.arg("--");
+            cmd.arg(filter);
+        }
+        return;
+    }
+
+    if args.build_script {
+        // The build script is expanded by the rustc wrapper.
+        return;
+    }
+
     cmd.arg("--");
 
     cmd.arg("-o");
     cmd.arg(outfile);
-    cmd.arg(ARG_Z_UNPRETTY_EXPANDED);
+    let unpretty = args.unpretty();
+    cmd.arg(format!("{}{}", ARG_Z_UNPRETTY, unpretty.name()));
+
+    // Only the crate being expanded sees these, unlike RUSTFLAGS which would
+    // cause all of its dependencies to be rebuilt.
+    for cfg in &args.cfg {
+        cmd.flag_value("--cfg", rustc_cfg(cfg));
+    }
+    cmd.args(&args.rustc_args);
+}
+
+// NAME=VALUE means NAME="VALUE" unless the value is already quoted.
+fn rustc_cfg(cfg: &str) -> String {
+    match cfg.split_once('=') {
+        Some((name, value)) if !value.starts_with('"') => {
+            format!("{}={:?}", name.trim(), value.trim())
+        }
+        _ => cfg.to_owned(),
+    }
 }
 
-fn needs_rustc_bootstrap() -> bool {
+fn needs_rustc_bootstrap(args: &Expand) -> bool {
     if env::var_os("RUSTC_BOOTSTRAP").is_some_and(|var| !var.is_empty()) {
         return false;
     }
 
+    // Probing takes a couple of cargo and rustc runs, so remember the answer.
+    let cache_dir = cache::dir(args);
+    let key = cache_dir.as_ref().and_then(|_| BootstrapKey::current());
+    if let (Some(cache_dir), Some(key)) = (&cache_dir, &key) {
+        if let Some(needs_rustc_bootstrap) = cache::load_bootstrap(cache_dir, key) {
+            return needs_rustc_bootstrap;
+        }
+    }
+    let needs_rustc_bootstrap = probe_rustc_bootstrap();
+    if let (Some(cache_dir), Some(key)) = (cache_dir, key) {
+        cache::store_bootstrap(&cache_dir, key, needs_rustc_bootstrap);
+    }
+    needs_rustc_bootstrap
+}
+
+fn probe_rustc_bootstrap() -> bool {
     let rustc = if let Some(rustc) = env::var_os("RUSTC") {
         PathBuf::from(rustc)
     } else {
@@ -605,52 +986,77 @@ fn print_command(cmd: &Command, color: Coloring) -> Result<()> {
     let _ = stream.set_color(ColorSpec::new().set_bold(true).set_fg(Some(Green)));
     let _ = write!(stream, "{:>12}", "Running");
     let _ = stream.reset();
-    let _ = writeln!(stream, " `cargo +nightly{}`", shell_words);
+    let toolchain = match env::var("RUSTUP_TOOLCHAIN") {
+        Ok(toolchain) => format!(" +{}", toolchain),
+        Err(_) => String::new(),
+    };
+    let _ = writeln!(stream, " `cargo{}{}`", toolchain, shell_words);
     Ok(())
 }
 
-fn filter_err(cmd: &mut Command) -> io::Result<i32> {
-    let mut child = cmd.stderr(Stdio::piped()).spawn()?;
-    let mut stderr = io::BufReader::new(child.stderr.take().unwrap());
-    let mut line = String::new();
-    while let Ok(n) = stderr.read_line(&mut line) {
-        if n == 0 {
-            break;
+// Cargo's own messages arrive on stderr, and the compiler's as JSON on stdout.
+// Returns the exit code and the errors that point at a location in the source.
+fn filter_err(cmd: &mut Command, args: &Expand) -> io::Result<(i32, Vec<Diagnostic>)> {
+    if args.lsp {
+        cmd.stdin(Stdio::null());
+    }
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stderr = io::BufReader::new(child.stderr.take().unwrap());
+    let generated = args.generated;
+    let stderr_thread = thread::spawn(move || {
+        for line in stderr.lines() {
+            let Ok(line) = line else { break };
+            if !ignore_cargo_err(&line, generated) {
+                let _ = writeln!(io::stderr(), "{}", line);
+            }
         }
-        if !ignore_cargo_err(&line) {
-            let _ = write!(io::stderr(), "{}", line);
+    });
+
+    let json = args.message_format == Some(MessageFormat::Json);
+    let mut diagnostics = Vec::new();
+    let stdout = io::BufReader::new(child.stdout.take().unwrap());
+    for line in stdout.lines() {
+        let line = line?;
+        let Ok(message) = serde_json::from_str::<diagnostics::Message>(&line) else {
+            // The language server's stdout is for its own messages only.
+            if args.lsp {
+                let _ = writeln!(io::stderr(), "{}", line);
+            } else {
+                let _ = writeln!(io::stdout(), "{}", line);
+            }
+            continue;
+        };
+        if message.is_noise() {
+            continue;
+        }
+        if json {
+            let _ = writeln!(io::stdout(), "{}", line);
+        } else if let Some(rendered) = message.rendered() {
+            let _ = write!(io::stderr(), "{}", rendered);
         }
-        line.clear();
+        diagnostics.extend(message.error());
     }
+
+    let _ = stderr_thread.join();
     let code = child.wait()?.code().unwrap_or(1);
-    Ok(code)
+    Ok((code, diagnostics))
 }
 
-fn ignore_cargo_err(line: &str) -> bool {
+fn ignore_cargo_err(line: &str, generated: bool) -> bool {
     if line.trim().is_empty() {
         return true;
     }
 
-    let discarded_lines = [
-        "ignoring specified output filename because multiple outputs were \
-         requested",
-        "ignoring specified output filename for 'link' output because multiple \
-         outputs were requested",
-        "ignoring --out-dir flag due to -o flag",
-        "ignoring -C extra-filename flag due to -o flag",
-        "due to multiple output types requested, the explicitly specified \
-         output file name will be adapted for each output type",
-        "warning emitted",
-        "warnings emitted",
-        ") generated ",
-    ];
-    for s in &discarded_lines {
-        if line.contains(s) {
-            return true;
-        }
+    // Warnings about the manifest of a --dep or --snippet package are about
+    // something the user did not write, like those cargo leaves out for
+    // packages from a registry.
+    if generated && line.starts_with("warning: ") && line.contains("Cargo.toml: ") {
+        return true;
     }
 
-    false
+    // Cargo's count of warnings per crate includes the noise that filter_err
+    // did not show.
+    line.contains(") generated ")
 }
 
 fn ignore_panic<F, T>(f: F) -> ThreadResult<T>
@@ -1,6 +1,7 @@
 use crate::error::Result;
 use serde::Deserialize;
 use std::env;
+use std::ffi::OsStr;
 use std::io::{self, ErrorKind};
 use std::path::{Path, PathBuf};
 
@@ -22,7 +23,7 @@ pub fn parse(manifest_path: Option<&Path>) -> Result<CargoManifest> {
     Ok(cargo_manifest)
 }
 
-fn find_cargo_manifest(manifest_path: Option<&Path>) -> io::Result<PathBuf> {
+pub fn find_cargo_manifest(manifest_path: Option<&Path>) -> io::Result<PathBuf> {
     if let Some(manifest_path) = manifest_path {
         return Ok(manifest_path.to_owned());
     }
@@ -40,3 +41,8 @@ fn find_cargo_manifest(manifest_path: Option<&Path>) -> io::Result<PathBuf> {
         };
     }
 }
+
+// A single-file package, i.e. a cargo script, which requires -Zscript.
+pub fn is_script(manifest_path: Option<&Path>) -> bool {
+    manifest_path.is_some_and(|path| path.extension() == Some(OsStr::new("rs")))
+}
@@ -0,0 +1,277 @@
+use crate::comments;
+use crate::config::Config;
+use crate::error::Result;
+use crate::metadata;
+use crate::opts::{Coloring, Expand};
+use crate::unparse::unparse_items;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use syn::{File, Item};
+
+// Expand once per --targets triple and show which items come out differently.
+pub fn expand_targets(
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+) -> Result<i32> {
+    let mut variants = Vec::new();
+    for target in &args.targets {
+        let mut target_args = args.clone();
+        target_args.targets.clear();
+        target_args.target = Some(target.clone());
+        variants.push((target.clone(), target_args));
+    }
+    expand_variants(variants, args, config, rustfmt, color)
+}
+
+// Expand once with no features, then once per combination of up to DEPTH of
+// the features in the package's [features] table, and show which items come
+// out differently.
+pub fn expand_features(
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+) -> Result<i32> {
+    let metadata = metadata::load(args)?;
+    let Some(package) = metadata.selected_package(args) else {
+        let _ = writeln!(
+            io::stderr(),
+            "ERROR: --feature-matrix requires selecting a single package with -p",
+        );
+        return Ok(1);
+    };
+    let features = &package.features;
+    let names: Vec<&str> = features.keys().map(String::as_str).collect();
+    let depth = args.feature_matrix.flatten().unwrap_or(1);
+
+    let mut combinations = vec![Vec::new()];
+    for size in 1..=depth.min(names.len()) {
+        combine(&names, size, &mut Vec::new(), &mut combinations);
+    }
+
+    let mut variants = Vec::new();
+    for combination in combinations {
+        let mut feature_args = args.clone();
+        feature_args.feature_matrix = None;
+        feature_args.no_default_features = true;
+        feature_args.features = vec![combination.join(",")];
+        let label = if combination.is_empty() {
+            "none".to_owned()
+        } else {
+            combination.join("+")
+        };
+        variants.push((label, feature_args));
+    }
+    expand_variants(variants, args, config, rustfmt, color)
+}
+
+// Every combination of `size` names, keeping them in the order given.
+fn combine<'a>(
+    names: &[&'a str],
+    size: usize,
+    prefix: &mut Vec<&'a str>,
+    combinations: &mut Vec<Vec<&'a str>>,
+) {
+    if prefix.len() == size {
+        combinations.push(prefix.clone());
+        return;
+    }
+    for (i, name) in names.iter().enumerate() {
+        prefix.push(name);
+        combine(&names[i + 1..], size, prefix, combinations);
+        prefix.pop();
+    }
+}
+
+fn expand_variants(
+    variants: Vec<(String, Expand)>,
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+) -> Result<i32> {
+    let mut expansions = Vec::new();
+    let mut failures = Vec::new();
+    let mut exit_code = 0;
+    for (label, variant_args) in variants {
+        let (expanded, code) = crate::expand(&variant_args, config, rustfmt.clone(), color)?;
+        if code != 0 {
+            exit_code = code;
+        }
+        if let Some(expanded) = expanded {
+            expansions.push((label, expanded));
+        } else {
+            let reason = format!("exit code {}, see the errors above", code);
+            failures.push((label, reason));
+        }
+    }
+
+    if expansions.is_empty() {
+        return Ok(if exit_code == 0 { 1 } else { exit_code });
+    }
+    let content = compare(&expansions, &failures);
+    crate::print_expanded(&content, args, config, color)?;
+    Ok(exit_code)
+}
+
+// Lists, for every item that is not the same in all the variants, which
+// variants have which version of it. If nothing differs, that is the whole
+// expansion. Variants that failed to expand are listed first, and then none
+// of the items can be said to be the same in all of them.
+pub fn compare(variants: &[(String, String)], failures: &[(String, String)]) -> String {
+    let mut items: Vec<(String, Vec<Option<String>>)> = Vec::new();
+    for (i, (_label, expanded)) in variants.iter().enumerate() {
+        let flattened = match syn::parse_file(expanded) {
+            Ok(file) => flatten(file),
+            // Not Rust syntax, as with --ugly or some --unpretty modes.
+            Err(_) => vec![("the expansion".to_owned(), expanded.clone())],
+        };
+        for (path, code) in flattened {
+            let index = items
+                .iter()
+                .position(|(p, _)| *p == path)
+                .unwrap_or_else(|| {
+                    items.push((path, vec![None; variants.len()]));
+                    items.len() - 1
+                });
+            items[index].1[i] = Some(code);
+        }
+    }
+
+    let mut content = String::new();
+    for (label, reason) in failures {
+        let _ = writeln!(content, "// ==== {} failed: {} ====", label, reason);
+    }
+    let mut differences = String::new();
+    for (path, versions) in &items {
+        if versions.iter().all(|version| *version == versions[0]) {
+            continue;
+        }
+        if !differences.is_empty() {
+            differences.push('\n');
+        }
+        let _ = writeln!(differences, "// ==== {} ====", path);
+        let mut shown = vec![false; versions.len()];
+        for i in 0..versions.len() {
+            if shown[i] {
+                continue;
+            }
+            let mut labels = Vec::new();
+            for j in i..versions.len() {
+                if versions[j] == versions[i] {
+                    shown[j] = true;
+                    labels.push(variants[j].0.as_str());
+                }
+            }
+            let _ = writeln!(differences, "// ---- {} ----", labels.join(", "));
+            match &versions[i] {
+                Some(code) => differences.push_str(code),
+                None => differences.push_str("// (absent)\n"),
+            }
+        }
+    }
+
+    let labels: Vec<&str> = variants.iter().map(|(label, _)| label.as_str()).collect();
+    if differences.is_empty() && failures.is_empty() && variants.len() == 1 {
+        differences.clone_from(&variants[0].1);
+    } else if differences.is_empty() && failures.is_empty() {
+        let _ = writeln!(
+            differences,
+            "// ==== identical for {} ====",
+            labels.join(", ")
+        );
+        differences.push_str(&variants[0].1);
+    } else if differences.is_empty() {
+        let _ = writeln!(differences, "// ==== {} ====", labels.join(", "));
+        differences.push_str(&variants[0].1);
+    }
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    content.push_str(&differences);
+    content
+}
+
+// Every item outside of inline modules, identified by its key in the module
+// it appears in, and unparsed on its own.
+fn flatten(file: File) -> Vec<(String, String)> {
+    let mut flattened = Vec::new();
+    flatten_items(file.items, "", &mut flattened);
+    flattened
+}
+
+fn flatten_items(items: Vec<Item>, module: &str, flattened: &mut Vec<(String, String)>) {
+    for item in items {
+        if let Item::Mod(syn::ItemMod {
+            ident,
+            content: Some((_brace, content)),
+            ..
+        }) = item
+        {
+            let module = if module.is_empty() {
+                ident.to_string()
+            } else {
+                format!("{}::{}", module, ident)
+            };
+            flatten_items(content, &module, flattened);
+            continue;
+        }
+        let key = comments::item_key(&item).unwrap_or_else(|| "macro".to_owned());
+        let label = if module.is_empty() {
+            key
+        } else {
+            format!("{} in mod {}", key, module)
+        };
+        // Items with the same key are told apart by the order they appear in.
+        let mut path = label.clone();
+        let mut n = 1;
+        while flattened.iter().any(|(p, _)| *p == path) {
+            n += 1;
+            path = format!("{} #{}", label, n);
+        }
+        flattened.push((path, unparse_items(vec![item])));
+    }
+}
+
+#[test]
+fn test_compare() {
+    let variants = [
+        ("a".to_owned(), "fn f() {}\nfn g() {}\n".to_owned()),
+        (
+            "b".to_owned(),
+            "fn f() {}\nfn g() -> u8 {\n    0\n}\n".to_owned(),
+        ),
+        ("c".to_owned(), "fn f() {}\n".to_owned()),
+        ("d".to_owned(), "fn f() {}\nfn g() {}\n".to_owned()),
+    ];
+    let expected = "// ==== fn g ====\n\
+                    // ---- a, d ----\n\
+                    fn g() {}\n\
+                    // ---- b ----\n\
+                    fn g() -> u8 {\n    0\n}\n\
+                    // ---- c ----\n\
+                    // (absent)\n";
+    assert_eq!(compare(&variants, &[]), expected);
+
+    let variants = [
+        ("a".to_owned(), "fn f() {}\n".to_owned()),
+        ("b".to_owned(), "fn f() {}\n".to_owned()),
+    ];
+    let expected = "// ==== identical for a, b ====\nfn f() {}\n";
+    assert_eq!(compare(&variants, &[]), expected);
+
+    let failures = [("c".to_owned(), "exit code 101".to_owned())];
+    let expected = "// ==== c failed: exit code 101 ====\n\
+                    \n\
+                    // ==== a, b ====\n\
+                    fn f() {}\n";
+    assert_eq!(compare(&variants, &failures), expected);
+    let expected = "// ==== c failed: exit code 101 ====\n\
+                    \n\
+                    // ==== a ====\n\
+                    fn f() {}\n";
+    assert_eq!(compare(&variants[..1], &failures), expected);
+}
@@ -0,0 +1,226 @@
+use crate::cmd::CommandExt as _;
+use crate::error::{Error, Result};
+use crate::manifest;
+use crate::opts::Expand;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+#[derive(Deserialize, Debug)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    pub workspace_members: Vec<String>,
+    pub workspace_root: PathBuf,
+    pub target_directory: PathBuf,
+    pub resolve: Option<Resolve>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Resolve {
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Node {
+    pub id: String,
+    pub features: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub id: String,
+    pub manifest_path: PathBuf,
+    pub source: Option<String>,
+    pub targets: Vec<Target>,
+    pub default_run: Option<String>,
+    pub edition: String,
+    pub dependencies: Vec<Dependency>,
+    pub features: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+    pub src_path: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Dependency {
+    pub name: String,
+    pub source: Option<String>,
+    pub req: String,
+    pub kind: Option<String>,
+    pub rename: Option<String>,
+    pub optional: bool,
+    pub uses_default_features: bool,
+    pub features: Vec<String>,
+    pub target: Option<String>,
+    pub registry: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+pub fn load(args: &Expand) -> Result<Metadata> {
+    let mut cmd = Command::new(crate::cargo_binary());
+    cmd.arg("metadata");
+    cmd.arg("--no-deps");
+    run(cmd, args)
+}
+
+/// Metadata including all dependencies, and the features they are resolved
+/// with for the selected features of the workspace.
+pub fn load_with_dependencies(args: &Expand) -> Result<Metadata> {
+    let mut cmd = Command::new(crate::cargo_binary());
+    cmd.arg("metadata");
+    for features in &args.features {
+        cmd.flag_value("--features", features);
+    }
+    if args.all_features {
+        cmd.arg("--all-features");
+    }
+    if args.no_default_features {
+        cmd.arg("--no-default-features");
+    }
+    if args.locked {
+        cmd.arg("--locked");
+    }
+    run(cmd, args)
+}
+
+fn run(mut cmd: Command, args: &Expand) -> Result<Metadata> {
+    cmd.flag_value("--format-version", "1");
+    for kv in &args.config {
+        cmd.flag_value("--config", kv);
+    }
+    if manifest::is_script(args.manifest_path.as_deref()) {
+        cmd.arg("-Zscript");
+        cmd.env("RUSTC_BOOTSTRAP", "1");
+    }
+    if let Some(manifest_path) = &args.manifest_path {
+        cmd.flag_value("--manifest-path", manifest_path);
+    }
+    if args.offline {
+        cmd.arg("--offline");
+    }
+    if args.frozen {
+        cmd.arg("--frozen");
+    }
+    cmd.stdin(Stdio::null());
+    cmd.stderr(Stdio::inherit());
+
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(Error::CargoMetadata);
+    }
+    let metadata: Metadata = serde_json::from_slice(&output.stdout)?;
+    Ok(metadata)
+}
+
+impl Metadata {
+    /// The package that `cargo rustc` would select for these args.
+    pub fn selected_package(&self, args: &Expand) -> Option<&Package> {
+        let members = || {
+            self.packages
+                .iter()
+                .filter(|package| self.workspace_members.contains(&package.id))
+        };
+
+        if let Some(Some(spec)) = &args.package {
+            return members().find(|package| package.matches_spec(spec));
+        }
+
+        let manifest_path = manifest::find_cargo_manifest(args.manifest_path.as_deref()).ok()?;
+        let manifest_path = manifest_path.canonicalize().ok()?;
+        if let Some(package) = members().find(|package| {
+            package
+                .manifest_path
+                .canonicalize()
+                .is_ok_and(|path| path == manifest_path)
+        }) {
+            return Some(package);
+        }
+
+        // Virtual workspace with a single member.
+        let mut members = members();
+        let package = members.next()?;
+        members.next().is_none().then_some(package)
+    }
+}
+
+impl Package {
+    pub fn matches_spec(&self, spec: &str) -> bool {
+        // A package ID spec URL, such as path+file:///path/to/dep#dep@1.0.0.
+        let spec = spec
+            .rsplit_once('#')
+            .map_or(spec, |(_url, fragment)| fragment);
+        match spec.split_once('@') {
+            Some((name, version)) => name == self.name && version == self.version,
+            None => spec == self.name,
+        }
+    }
+
+    pub fn lib(&self) -> Option<&Target> {
+        self.targets.iter().find(|target| target.is_lib())
+    }
+
+    pub fn find_target(&self, kind: &str, name: &str) -> Option<&Target> {
+        self.targets
+            .iter()
+            .find(|target| target.name == name && target.kind.iter().any(|k| k == kind))
+    }
+
+    /// The target that `cargo rustc` would build for these args.
+    pub fn selected_target(&self, args: &Expand) -> Option<&Target> {
+        if args.lib {
+            return self.lib();
+        }
+
+        if args.build_script {
+            return self.find_target("custom-build", "build-script-build");
+        }
+
+        let explicit = [
+            ("bin", &args.bin),
+            ("example", &args.example),
+            ("test", &args.test),
+            ("bench", &args.bench),
+        ];
+        for (kind, opt_name) in explicit {
+            if let Some(opt_name) = opt_name {
+                return match opt_name {
+                    Some(name) => self.find_target(kind, name),
+                    None => None,
+                };
+            }
+        }
+
+        if let Some(default_run) = &self.default_run {
+            return self.find_target("bin", default_run);
+        }
+
+        if let Some(lib) = self.lib() {
+            return Some(lib);
+        }
+
+        let mut bins = self
+            .targets
+            .iter()
+            .filter(|target| target.kind.iter().any(|kind| kind == "bin"));
+        let bin = bins.next()?;
+        bins.next().is_none().then_some(bin)
+    }
+}
+
+impl Target {
+    pub fn is_lib(&self) -> bool {
+        self.kind.iter().any(|kind| {
+            matches!(
+                kind.as_str(),
+                "lib" | "rlib" | "dylib" | "cdylib" | "staticlib" | "proc-macro",
+            )
+        })
+    }
+}
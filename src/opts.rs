@@ -1,5 +1,6 @@
-use clap::{Parser, ValueEnum};
-use std::path::PathBuf;
+use clap::error::ErrorKind;
+use clap::{CommandFactory as _, Parser, ValueEnum};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use syn_select::Selector;
 
@@ -24,20 +25,78 @@ pub enum Subcommand {
     Expand(Expand),
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 pub struct Expand {
     /// Do not attempt to run rustfmt
     #[arg(long)]
     pub ugly: bool,
 
+    /// Remove doc comments from the expanded code
+    #[arg(long)]
+    pub strip_docs: bool,
+
+    /// Restore comments from the original source on items unchanged by expansion
+    #[arg(long)]
+    pub comments: bool,
+
+    /// Show only the public API of the expanded crate
+    #[arg(long)]
+    pub public_api: bool,
+
+    /// Collapse the content of modules nested more than N levels deep
+    #[arg(long, value_name = "N")]
+    pub depth: Option<usize>,
+
+    /// Replace the body of every function and method with `{ ... }`
+    #[arg(long)]
+    pub signatures: bool,
+
+    /// Elide string and array literals longer than N characters or elements
+    #[arg(long, value_name = "N")]
+    pub elide_literals: Option<usize>,
+
+    /// Compiler representation to print (expanded, expanded,hygiene,
+    /// expanded,identified, hir, hir-tree, thir-tree, mir, ast-tree)
+    #[arg(long, value_name = "MODE", hide_possible_values = true)]
+    pub unpretty: Option<Unpretty>,
+
+    /// Distinguish identifiers that have the same name but different hygiene
+    #[arg(long, conflicts_with = "unpretty")]
+    pub hygiene: bool,
+
     /// Select syntax highlighting theme
     #[arg(long, value_name = "NAME")]
     pub theme: Option<String>,
 
+    /// Browse the expanded modules and items in an interactive terminal UI
+    #[arg(long, conflicts_with_all = ["watch", "message_format"])]
+    pub tui: bool,
+
     /// Print available syntax highlighting theme names
     #[arg(long)]
     pub themes: bool,
 
+    /// Expand again whenever the package's sources or those of its path
+    /// dependencies change
+    #[arg(long, conflicts_with_all = ["snippet", "dep"])]
+    pub watch: bool,
+
+    /// Run a language server on stdin and stdout that shows the expansion of
+    /// the item under the cursor
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "watch", "tui", "message_format", "targets", "feature_matrix", "workspace",
+            "all_targets", "snippet", "dep", "doc", "build_script", "item",
+        ],
+    )]
+    pub lsp: bool,
+
+    /// Expand from scratch, without reusing or saving the results of earlier
+    /// runs
+    #[arg(long)]
+    pub no_cache: bool,
+
     /// Print command lines as they are executed
     #[arg(long)]
     pub verbose: bool,
@@ -50,6 +109,14 @@ pub struct Expand {
     #[arg(long, value_name = "WHEN", hide_possible_values = true)]
     pub color: Option<Coloring>,
 
+    /// Error format (human, json)
+    #[arg(long, value_name = "FMT", hide_possible_values = true)]
+    pub message_format: Option<MessageFormat>,
+
+    /// Rustup toolchain to expand with, e.g. nightly-2025-01-01
+    #[arg(long, value_name = "NAME")]
+    pub toolchain: Option<String>,
+
     /// Override a configuration value
     #[arg(long, value_name = "KEY=VALUE")]
     pub config: Vec<String>,
@@ -66,6 +133,23 @@ pub struct Expand {
     #[arg(short, long, value_name = "SPEC", num_args = 0..=1, help_heading = PACKAGE_SELECTION)]
     pub package: Option<Option<String>>,
 
+    /// Expand every package in the workspace
+    #[arg(
+        long,
+        help_heading = PACKAGE_SELECTION,
+        conflicts_with_all = ["package", "doc", "build_script", "snippet"],
+    )]
+    pub workspace: bool,
+
+    /// Dependency to expand, from the local registry cache or checkout
+    #[arg(
+        long,
+        value_name = "NAME[@VERSION]",
+        help_heading = PACKAGE_SELECTION,
+        conflicts_with_all = ["package", "snippet", "workspace"],
+    )]
+    pub dep: Option<String>,
+
     /// Expand only this package's library
     #[arg(long, help_heading = TARGET_SELECTION)]
     pub lib: bool,
@@ -90,6 +174,42 @@ pub struct Expand {
     #[arg(long, value_name = "NAME", num_args = 0..=1, help_heading = TARGET_SELECTION)]
     pub bench: Option<Option<String>>,
 
+    /// Expand every lib, bin, example, test and bench target
+    #[arg(
+        long,
+        help_heading = TARGET_SELECTION,
+        conflicts_with_all = ["lib", "bin", "example", "test", "bench", "doc", "build_script", "snippet"],
+    )]
+    pub all_targets: bool,
+
+    /// Expand this package's doctests, or only those whose name contains FILTER
+    #[arg(
+        long,
+        value_name = "FILTER",
+        num_args = 0..=1,
+        help_heading = TARGET_SELECTION,
+        conflicts_with_all = ["lib", "bin", "example", "test", "tests", "bench", "item"],
+    )]
+    pub doc: Option<Option<String>>,
+
+    /// Expand this package's build script
+    #[arg(
+        long,
+        help_heading = TARGET_SELECTION,
+        conflicts_with_all = ["lib", "bin", "example", "test", "tests", "bench", "doc"],
+    )]
+    pub build_script: bool,
+
+    /// Expand a snippet of code (`-` for stdin) in the context of this package
+    /// and its dependencies
+    #[arg(
+        long,
+        value_name = "CODE",
+        help_heading = TARGET_SELECTION,
+        conflicts_with_all = ["lib", "bin", "example", "test", "tests", "bench", "doc", "build_script"],
+    )]
+    pub snippet: Option<String>,
+
     /// Space or comma separated list of features to activate
     #[arg(short = 'F', long, value_name = "FEATURES", help_heading = FEATURE_SELECTION)]
     pub features: Vec<String>,
@@ -102,6 +222,18 @@ pub struct Expand {
     #[arg(long, help_heading = FEATURE_SELECTION)]
     pub no_default_features: bool,
 
+    /// Expand with each feature on its own, or every combination of up to
+    /// DEPTH features, and show the items that differ
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        num_args = 0..=1,
+        require_equals = true,
+        help_heading = FEATURE_SELECTION,
+        conflicts_with_all = ["features", "all_features", "no_default_features", "targets", "workspace", "all_targets"],
+    )]
+    pub feature_matrix: Option<Option<usize>>,
+
     /// Number of parallel jobs, defaults to # of CPUs
     #[arg(short, long, value_name = "N", help_heading = COMPILATION_OPTIONS)]
     pub jobs: Option<u64>,
@@ -122,6 +254,25 @@ pub struct Expand {
     #[arg(long, value_name = "TARGET", help_heading = COMPILATION_OPTIONS)]
     pub target: Option<String>,
 
+    /// Expand for each target triple and show the items that differ
+    #[arg(
+        long,
+        value_name = "TARGETS",
+        value_delimiter = ',',
+        conflicts_with_all = ["target", "workspace", "all_targets"],
+        help_heading = COMPILATION_OPTIONS,
+    )]
+    pub targets: Vec<String>,
+
+    /// Configure the crate being expanded with `cfg(NAME)` or `cfg(NAME = "VALUE")`
+    #[arg(
+        long,
+        value_name = "NAME[=VALUE]",
+        conflicts_with_all = ["doc", "build_script"],
+        help_heading = COMPILATION_OPTIONS,
+    )]
+    pub cfg: Vec<String>,
+
     /// Directory for all generated artifacts
     #[arg(long, value_name = "DIRECTORY", help_heading = COMPILATION_OPTIONS)]
     pub target_dir: Option<PathBuf>,
@@ -146,9 +297,32 @@ pub struct Expand {
     #[arg(long, help_heading = MANIFEST_OPTIONS)]
     pub frozen: bool,
 
-    /// Local path to module or other named item to expand, e.g. os::unix::ffi
-    #[arg(value_name = "ITEM", value_parser = parse_selector)]
+    /// Local path to module or other named item to expand, e.g. os::unix::ffi,
+    /// or a single-file package to expand, e.g. script.rs
+    #[arg(id = "item", value_name = "ITEM", value_parser = parse_positional)]
+    positional: Option<Positional>,
+
+    /// Extra arguments for rustc, after `--`
+    #[arg(
+        value_name = "RUSTC_ARGS",
+        last = true,
+        conflicts_with_all = ["doc", "build_script"],
+    )]
+    pub rustc_args: Vec<String>,
+
+    #[arg(skip)]
     pub item: Option<Selector>,
+
+    /// Set when expanding a package generated in a temporary directory, for
+    /// --snippet and --dep
+    #[arg(skip)]
+    pub generated: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Positional {
+    Item(Selector),
+    Script(PathBuf),
 }
 
 #[derive(ValueEnum, Debug, Clone, Copy)]
@@ -158,6 +332,102 @@ pub enum Coloring {
     Never,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl Subcommand {
+    pub fn parse_expand() -> Expand {
+        let Subcommand::Expand(mut args) = Subcommand::parse();
+        match args.positional.take() {
+            Some(Positional::Item(selector)) => args.item = Some(selector),
+            Some(Positional::Script(path)) => {
+                if args.manifest_path.is_some() {
+                    Subcommand::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "the argument '[ITEM]' cannot be a script when used with '--manifest-path'",
+                        )
+                        .exit();
+                }
+                args.manifest_path = Some(path);
+            }
+            None => {}
+        }
+        args
+    }
+}
+
+impl Expand {
+    pub fn unpretty(&self) -> Unpretty {
+        if self.hygiene {
+            Unpretty::ExpandedHygiene
+        } else {
+            self.unpretty.unwrap_or_default()
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Unpretty {
+    #[default]
+    Expanded,
+    #[value(name = "expanded,hygiene")]
+    ExpandedHygiene,
+    #[value(name = "expanded,identified")]
+    ExpandedIdentified,
+    Hir,
+    HirTree,
+    ThirTree,
+    Mir,
+    AstTree,
+}
+
+impl Unpretty {
+    pub fn name(self) -> &'static str {
+        match self {
+            Unpretty::Expanded => "expanded",
+            Unpretty::ExpandedHygiene => "expanded,hygiene",
+            Unpretty::ExpandedIdentified => "expanded,identified",
+            Unpretty::Hir => "hir",
+            Unpretty::HirTree => "hir-tree",
+            Unpretty::ThirTree => "thir-tree",
+            Unpretty::Mir => "mir",
+            Unpretty::AstTree => "ast-tree",
+        }
+    }
+
+    /// Whether the output is Rust code that can be parsed and reformatted.
+    /// The annotations added by hygiene and identified modes are comments,
+    /// which would be lost by reformatting.
+    pub fn is_parseable(self) -> bool {
+        match self {
+            Unpretty::Expanded | Unpretty::Hir => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the output resembles Rust closely enough to highlight as Rust.
+    pub fn is_rust_like(self) -> bool {
+        match self {
+            Unpretty::HirTree | Unpretty::ThirTree | Unpretty::AstTree => false,
+            _ => true,
+        }
+    }
+}
+
+// No item path ends in `.rs`, so this is unambiguously a single-file package.
+fn parse_positional(s: &str) -> Result<Positional, <Selector as FromStr>::Err> {
+    let extension = Path::new(s).extension();
+    if extension.is_some_and(|extension| extension.eq_ignore_ascii_case("rs")) {
+        Ok(Positional::Script(PathBuf::from(s)))
+    } else {
+        parse_selector(s).map(Positional::Item)
+    }
+}
+
 fn parse_selector(s: &str) -> Result<Selector, <Selector as FromStr>::Err> {
     if s.starts_with("::") {
         s[2..].parse()
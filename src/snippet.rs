@@ -0,0 +1,255 @@
+use crate::error::Result;
+use crate::manifest;
+use crate::metadata::{self, Dependency, Package};
+use crate::opts::Expand;
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use tempfile::TempDir;
+use toml::{Table, Value};
+
+// Turn `cargo expand --snippet CODE` into an ordinary expansion of a throwaway
+// library crate containing CODE, which depends on the selected package and on
+// everything that package depends on. Returns None if an error was reported.
+pub fn create(args: &mut Expand) -> Result<Option<TempDir>> {
+    let mut code = args.snippet.take().unwrap_or_default();
+    if code == "-" {
+        code.clear();
+        io::stdin().read_to_string(&mut code)?;
+    }
+
+    // Outside of any package, the snippet gets only the standard library.
+    let metadata = match manifest::find_cargo_manifest(args.manifest_path.as_deref()) {
+        Ok(_) => Some(metadata::load(args)?),
+        Err(_) => None,
+    };
+    let package = if let Some(metadata) = &metadata {
+        let Some(package) = metadata.selected_package(args) else {
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: --snippet requires selecting a single package with -p",
+            );
+            return Ok(None);
+        };
+        Some(package)
+    } else {
+        None
+    };
+
+    let mut dependencies = Table::new();
+    let mut dev_dependencies = Table::new();
+    let mut target = Table::new();
+    if let Some(package) = package {
+        if package.lib().is_some() {
+            let dependency = package_dependency(package, args);
+            dependencies.insert(package.name.clone(), Value::Table(dependency));
+        }
+        let enabled = enabled_features(package, args);
+        for dep in &package.dependencies {
+            if dep.kind.as_deref() == Some("build") {
+                continue;
+            }
+            let key = dep.rename.as_ref().unwrap_or(&dep.name);
+            // Optional dependencies that the package is not built with would
+            // only change how its other dependencies get resolved.
+            if dep.optional && !enabled.contains(&format!("dep:{}", key)) {
+                continue;
+            }
+            let dev = dep.kind.as_deref() == Some("dev");
+            let table = match &dep.target {
+                Some(cfg) => target
+                    .entry(cfg.clone())
+                    .or_insert_with(|| Value::Table(Table::new()))
+                    .as_table_mut()
+                    .unwrap()
+                    .entry(if dev {
+                        "dev-dependencies"
+                    } else {
+                        "dependencies"
+                    })
+                    .or_insert_with(|| Value::Table(Table::new()))
+                    .as_table_mut()
+                    .unwrap(),
+                None if dev => &mut dev_dependencies,
+                None => &mut dependencies,
+            };
+            if !table.contains_key(key) {
+                table.insert(key.clone(), Value::Table(dependency(dep)));
+            }
+        }
+    }
+
+    let mut package_table = Table::new();
+    package_table.insert("name".to_owned(), "cargo-expand-snippet".into());
+    package_table.insert("version".to_owned(), "0.0.0".into());
+    let edition = package.map_or("2021", |package| &package.edition);
+    package_table.insert("edition".to_owned(), edition.into());
+    package_table.insert("publish".to_owned(), false.into());
+
+    // Warnings about the snippet not using what it defines are just noise.
+    let mut lints = Table::new();
+    lints.insert("unused".to_owned(), "allow".into());
+
+    let mut cargo_toml = Table::new();
+    cargo_toml.insert("package".to_owned(), Value::Table(package_table));
+    cargo_toml.insert("dependencies".to_owned(), Value::Table(dependencies));
+    if !dev_dependencies.is_empty() {
+        cargo_toml.insert(
+            "dev-dependencies".to_owned(),
+            Value::Table(dev_dependencies),
+        );
+    }
+    if !target.is_empty() {
+        cargo_toml.insert("target".to_owned(), Value::Table(target));
+    }
+    cargo_toml.insert(
+        "lints".to_owned(),
+        Value::Table(Table::from_iter([("rust".to_owned(), Value::Table(lints))])),
+    );
+    cargo_toml.insert("workspace".to_owned(), Value::Table(Table::new()));
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("cargo-expand-snippet");
+    let dir = builder.tempdir()?;
+    let manifest_path = dir.path().join("Cargo.toml");
+    fs_err::write(&manifest_path, toml::to_string(&cargo_toml)?)?;
+    fs_err::create_dir(dir.path().join("src"))?;
+    fs_err::write(dir.path().join("src").join("lib.rs"), code)?;
+
+    if let Some(metadata) = &metadata {
+        // Resolve the same dependency versions as the package.
+        let lockfile = metadata.workspace_root.join("Cargo.lock");
+        if lockfile.exists() {
+            fs_err::copy(lockfile, dir.path().join("Cargo.lock"))?;
+        }
+        // Reuse the package's already compiled dependencies.
+        if args.target_dir.is_none() {
+            args.target_dir = Some(metadata.target_directory.clone());
+        }
+    }
+
+    // Features were applied to the dependency on the package above.
+    args.manifest_path = Some(manifest_path);
+    args.package = None;
+    args.lib = true;
+    args.features.clear();
+    args.all_features = false;
+    args.no_default_features = false;
+
+    args.generated = true;
+
+    Ok(Some(dir))
+}
+
+fn package_dependency(package: &Package, args: &Expand) -> Table {
+    let mut table = Table::new();
+    let dir = package.manifest_path.parent().unwrap();
+    table.insert("path".to_owned(), dir.to_string_lossy().into_owned().into());
+    let features: Vec<Value> = if args.all_features {
+        package
+            .features
+            .keys()
+            .map(|feature| feature.as_str().into())
+            .collect()
+    } else {
+        args.features
+            .iter()
+            .flat_map(|features| features.split([' ', ',']))
+            .filter(|feature| !feature.is_empty())
+            .map(Value::from)
+            .collect()
+    };
+    if !features.is_empty() {
+        table.insert("features".to_owned(), Value::Array(features));
+    }
+    if args.no_default_features {
+        table.insert("default-features".to_owned(), false.into());
+    }
+    table
+}
+
+// The features of the package that these args turn on, directly or through
+// other features, plus "dep:NAME" for each optional dependency turned on.
+fn enabled_features(package: &Package, args: &Expand) -> BTreeSet<String> {
+    let mut pending: Vec<String> = if args.all_features {
+        let features = package.features.keys().cloned();
+        let optional = package.dependencies.iter().filter(|dep| dep.optional);
+        let deps = optional.map(|dep| format!("dep:{}", dep.rename.as_ref().unwrap_or(&dep.name)));
+        features.chain(deps).collect()
+    } else {
+        let prefix = format!("{}/", package.name);
+        args.features
+            .iter()
+            .flat_map(|features| features.split([' ', ',']))
+            .filter(|feature| !feature.is_empty())
+            .map(|feature| feature.strip_prefix(&prefix).unwrap_or(feature).to_owned())
+            .collect()
+    };
+    if !args.no_default_features {
+        pending.push("default".to_owned());
+    }
+
+    let mut enabled = BTreeSet::new();
+    while let Some(feature) = pending.pop() {
+        if !enabled.insert(feature.clone()) {
+            continue;
+        }
+        if let Some(values) = package.features.get(&feature) {
+            for value in values {
+                if let Some((dep, _feature)) = value.split_once('/') {
+                    // "dep?/feature" does not turn the dependency on.
+                    if !dep.ends_with('?') {
+                        pending.push(format!("dep:{}", dep));
+                    }
+                } else {
+                    pending.push(value.clone());
+                }
+            }
+        } else if !feature.starts_with("dep:") {
+            // The implicit feature of an optional dependency.
+            pending.push(format!("dep:{}", feature));
+        }
+    }
+    enabled
+}
+
+fn dependency(dep: &Dependency) -> Table {
+    let mut table = Table::new();
+    if dep.rename.is_some() {
+        table.insert("package".to_owned(), dep.name.as_str().into());
+    }
+
+    let git = dep
+        .source
+        .as_deref()
+        .and_then(|source| source.strip_prefix("git+"));
+    if let Some(path) = &dep.path {
+        table.insert(
+            "path".to_owned(),
+            path.to_string_lossy().into_owned().into(),
+        );
+    } else if let Some(git) = git {
+        let git = git.split('#').next().unwrap();
+        let (url, query) = git.split_once('?').unwrap_or((git, ""));
+        table.insert("git".to_owned(), url.into());
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            if matches!(key, "branch" | "tag" | "rev") {
+                table.insert(key.to_owned(), value.into());
+            }
+        }
+    } else if let Some(registry) = &dep.registry {
+        table.insert("registry-index".to_owned(), registry.as_str().into());
+    }
+
+    let is_registry = dep.path.is_none() && git.is_none();
+    if is_registry || dep.req != "*" {
+        table.insert("version".to_owned(), dep.req.as_str().into());
+    }
+    if !dep.features.is_empty() {
+        let features = dep.features.iter().map(|f| f.as_str().into()).collect();
+        table.insert("features".to_owned(), Value::Array(features));
+    }
+    if !dep.uses_default_features {
+        table.insert("default-features".to_owned(), false.into());
+    }
+    table
+}
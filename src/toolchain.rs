@@ -0,0 +1,53 @@
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Use the given rustup toolchain's cargo, rustc and rustfmt, rather than
+// whichever ones the rustup proxies would pick. Everything downstream already
+// finds these through $CARGO, $RUSTC and $RUSTFMT, and $RUSTUP_TOOLCHAIN makes
+// build scripts and proc macros that run `cargo` or `rustc` agree. Returns
+// false if an error was reported.
+pub fn select(name: &str) -> bool {
+    let Some(cargo) = which(name, "cargo") else {
+        return false;
+    };
+    let Some(rustc) = which(name, "rustc") else {
+        return false;
+    };
+    env::set_var("CARGO", cargo);
+    env::set_var("RUSTC", rustc);
+    // Only needed with rustfmt=true, which reports it missing.
+    if env::var_os("RUSTFMT").is_none() {
+        if let Some(rustfmt) = which(name, "rustfmt") {
+            env::set_var("RUSTFMT", rustfmt);
+        }
+    }
+    env::set_var("RUSTUP_TOOLCHAIN", name);
+    true
+}
+
+fn which(toolchain: &str, binary: &str) -> Option<PathBuf> {
+    let mut cmd = Command::new("rustup");
+    cmd.args(["which", "--toolchain", toolchain, binary]);
+    cmd.env_remove("RUST_BACKTRACE");
+    cmd.stdin(Stdio::null());
+    let output = match cmd.output() {
+        Ok(output) => output,
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "ERROR: failed to run rustup: {}", err);
+            return None;
+        }
+    };
+    if !output.status.success() {
+        if binary != "rustfmt" {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = stderr.lines().next().unwrap_or_default();
+            let message = message.strip_prefix("error: ").unwrap_or(message);
+            let _ = writeln!(io::stderr(), "ERROR: {}", message);
+        }
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(PathBuf::from(stdout.trim_end()))
+}
@@ -0,0 +1,280 @@
+use crate::comments;
+use crate::config::Config;
+use crate::error::Result;
+use crate::opts::{Coloring, Expand};
+use crate::unparse::unparse_items;
+use crate::Highlighter;
+use bat::PagingMode;
+use bat::WrappingMode;
+use console::{Alignment, Key, Term};
+use std::fmt::Write as _;
+use std::io::Write;
+use syn::{File, Item};
+
+// Browse the expanded code as a tree of modules and items on the left, with
+// the highlighted code of the selected one on the right.
+pub fn browse(content: &str, args: &Expand, config: &Config, color: Coloring) -> Result<()> {
+    let mut tree = match syn::parse_file(content) {
+        Ok(file) => Tree::new(file),
+        // Not Rust syntax, as with --ugly or some --unpretty modes.
+        Err(_) => Tree { nodes: Vec::new() },
+    };
+    if tree.nodes.is_empty() {
+        let node = Node::leaf("expansion".to_owned(), 0, content.to_owned());
+        tree.nodes.push(node);
+    }
+
+    let highlighter = Highlighter::new(args, config, color)?.map(|mut highlighter| {
+        highlighter.config.paging_mode = PagingMode::Never;
+        highlighter.config.wrapping_mode = WrappingMode::NoWrapping(true);
+        highlighter
+    });
+
+    let mut term = Term::stdout();
+    let _ = write!(term, "\x1b[?1049h\x1b[?25l");
+    let result = run(&mut term, &mut tree, highlighter.as_ref());
+    let _ = write!(term, "\x1b[?25h\x1b[?1049l");
+    let _ = term.flush();
+    result?;
+    Ok(())
+}
+
+struct Tree {
+    // In the order they appear in the code, each followed by its descendants.
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    label: String,
+    depth: usize,
+    code: String,
+    has_children: bool,
+    expanded: bool,
+    highlighted: Option<Vec<String>>,
+}
+
+impl Node {
+    fn leaf(label: String, depth: usize, code: String) -> Self {
+        Node {
+            label,
+            depth,
+            code,
+            has_children: false,
+            expanded: false,
+            highlighted: None,
+        }
+    }
+}
+
+impl Tree {
+    fn new(file: File) -> Self {
+        let mut tree = Tree { nodes: Vec::new() };
+        for item in file.items {
+            tree.push_item(item, 0);
+        }
+        tree
+    }
+
+    fn push_item(&mut self, item: Item, depth: usize) {
+        let label = tidy(&label(&item));
+        let code = unparse_items(vec![item.clone()]);
+        let index = self.nodes.len();
+        self.nodes.push(Node::leaf(label, depth, code));
+        match item {
+            Item::Mod(item) => {
+                for item in item.content.into_iter().flat_map(|(_brace, items)| items) {
+                    self.push_item(item, depth + 1);
+                }
+            }
+            Item::Impl(item) => {
+                for impl_item in &item.items {
+                    let label =
+                        comments::impl_item_key(impl_item).unwrap_or_else(|| "macro".to_owned());
+                    let mut item = item.clone();
+                    item.items = vec![impl_item.clone()];
+                    let code = unparse_items(vec![Item::Impl(item)]);
+                    self.nodes.push(Node::leaf(tidy(&label), depth + 1, code));
+                }
+            }
+            Item::Trait(item) => {
+                for trait_item in &item.items {
+                    let label =
+                        comments::trait_item_key(trait_item).unwrap_or_else(|| "macro".to_owned());
+                    let mut item = item.clone();
+                    item.items = vec![trait_item.clone()];
+                    let code = unparse_items(vec![Item::Trait(item)]);
+                    self.nodes.push(Node::leaf(tidy(&label), depth + 1, code));
+                }
+            }
+            _ => {}
+        }
+        self.nodes[index].has_children = self.nodes.len() > index + 1;
+    }
+
+    // The nodes not hidden inside a collapsed ancestor.
+    fn visible(&self) -> Vec<usize> {
+        let mut visible = Vec::new();
+        let mut hidden_below = None;
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(depth) = hidden_below {
+                if node.depth > depth {
+                    continue;
+                }
+                hidden_below = None;
+            }
+            visible.push(i);
+            if node.has_children && !node.expanded {
+                hidden_below = Some(node.depth);
+            }
+        }
+        visible
+    }
+
+    fn parent(&self, i: usize) -> Option<usize> {
+        let depth = self.nodes[i].depth;
+        (0..i).rev().find(|&j| self.nodes[j].depth < depth)
+    }
+
+    fn lines(&mut self, i: usize, highlighter: Option<&Highlighter>) -> &[String] {
+        let node = &mut self.nodes[i];
+        node.highlighted.get_or_insert_with(|| {
+            let code = match highlighter {
+                Some(highlighter) => highlighter.highlight(&node.code),
+                None => node.code.clone(),
+            };
+            code.lines().map(str::to_owned).collect()
+        })
+    }
+}
+
+fn label(item: &Item) -> String {
+    if let Some(key) = comments::item_key(item) {
+        return key;
+    }
+    match item {
+        Item::Macro(item) => match &item.ident {
+            Some(ident) => format!("macro_rules! {}", ident),
+            None => "macro".to_owned(),
+        },
+        Item::ForeignMod(_) => "extern".to_owned(),
+        _ => "item".to_owned(),
+    }
+}
+
+// Keys are made of tokens, as in `impl :: core :: fmt :: Debug for S`.
+fn tidy(label: &str) -> String {
+    let mut tidy = String::new();
+    let mut tokens = label.split(' ').peekable();
+    while let Some(token) = tokens.next() {
+        tidy.push_str(token);
+        let next = tokens.peek().copied().unwrap_or_default();
+        let keyword = matches!(token, "impl" | "for" | "dyn" | "as" | "use" | "where");
+        let joined = matches!(token, "::" | "<" | "&")
+            || matches!(next, "::" | "<" | "," | "(")
+                && !keyword
+                && token.ends_with(|ch: char| ch.is_alphanumeric() || ch == '_' || ch == '>')
+            || matches!(next, ">" | ")");
+        if !joined && tokens.peek().is_some() {
+            tidy.push(' ');
+        }
+    }
+    tidy
+}
+
+fn run(term: &mut Term, tree: &mut Tree, highlighter: Option<&Highlighter>) -> Result<()> {
+    let mut selected = 0;
+    let mut tree_scroll = 0;
+    let mut code_scroll = 0;
+    loop {
+        let visible = tree.visible();
+        selected = selected.min(visible.len() - 1);
+        let (height, width) = term.size();
+        let rows = usize::from(height).saturating_sub(1).max(1);
+        let width = usize::from(width);
+        let tree_width = (width / 3).clamp(20, 50).min(width);
+        let code_width = width.saturating_sub(tree_width + 1);
+
+        if selected < tree_scroll {
+            tree_scroll = selected;
+        } else if selected >= tree_scroll + rows {
+            tree_scroll = selected + 1 - rows;
+        }
+        let lines = tree.lines(visible[selected], highlighter).to_vec();
+        code_scroll = code_scroll.min(lines.len().saturating_sub(rows));
+
+        let mut frame = String::from("\x1b[H");
+        for row in 0..rows {
+            let entry = visible.get(tree_scroll + row).map(|&i| {
+                let node = &tree.nodes[i];
+                let marker = if !node.has_children {
+                    ' '
+                } else if node.expanded {
+                    '▾'
+                } else {
+                    '▸'
+                };
+                let text = format!("{}{} {}", "  ".repeat(node.depth), marker, node.label);
+                (tree_scroll + row == selected, text)
+            });
+            let (is_selected, text) = entry.unwrap_or_default();
+            let text = console::pad_str(&text, tree_width, Alignment::Left, Some("…"));
+            if is_selected {
+                let _ = write!(frame, "\x1b[7m{}\x1b[0m", text);
+            } else {
+                frame.push_str(&text);
+            }
+            frame.push('│');
+            if let Some(line) = lines.get(code_scroll + row) {
+                frame.push_str(&console::truncate_str(line, code_width, ""));
+                frame.push_str("\x1b[0m");
+            }
+            frame.push_str("\x1b[K\r\n");
+        }
+        let help = " ↑↓ select  ←→ collapse/expand  PgUp/PgDn scroll code  q quit";
+        let help = console::pad_str(help, width, Alignment::Left, Some("…"));
+        let _ = write!(frame, "\x1b[7m{}\x1b[0m", help);
+        write!(term, "{}", frame)?;
+        term.flush()?;
+
+        let i = visible[selected];
+        // Raw, so that Ctrl-C gets here and the terminal is restored.
+        match term.read_key_raw()? {
+            Key::Char('q') | Key::Escape | Key::CtrlC => return Ok(()),
+            Key::ArrowUp | Key::Char('k') if selected > 0 => {
+                selected -= 1;
+                code_scroll = 0;
+            }
+            Key::ArrowDown | Key::Char('j') if selected + 1 < visible.len() => {
+                selected += 1;
+                code_scroll = 0;
+            }
+            Key::Home => {
+                selected = 0;
+                code_scroll = 0;
+            }
+            Key::End => {
+                selected = visible.len() - 1;
+                code_scroll = 0;
+            }
+            Key::ArrowLeft | Key::Char('h') => {
+                if tree.nodes[i].has_children && tree.nodes[i].expanded {
+                    tree.nodes[i].expanded = false;
+                } else if let Some(parent) = tree.parent(i) {
+                    selected = visible.iter().position(|&v| v == parent).unwrap_or(0);
+                    code_scroll = 0;
+                }
+            }
+            Key::ArrowRight | Key::Char('l') | Key::Enter if tree.nodes[i].has_children => {
+                if tree.nodes[i].expanded {
+                    selected += 1;
+                    code_scroll = 0;
+                } else {
+                    tree.nodes[i].expanded = true;
+                }
+            }
+            Key::PageDown | Key::Char(' ') => code_scroll += rows,
+            Key::PageUp => code_scroll = code_scroll.saturating_sub(rows),
+            _ => {}
+        }
+    }
+}
@@ -18,6 +18,16 @@ pub(crate) fn unparse_maximal(syntax_tree: &File) -> String {
     prettyplease::unparse(&redacted)
 }
 
+// A file consisting of just these items.
+pub(crate) fn unparse_items(items: Vec<Item>) -> String {
+    unparse_maximal(&File {
+        shebang: None,
+        frontmatter: None,
+        attrs: Vec::new(),
+        items,
+    })
+}
+
 struct UnparseMaximal;
 
 impl Fold for UnparseMaximal {
@@ -0,0 +1,49 @@
+use crate::cache;
+use crate::config::Config;
+use crate::error::Result;
+use crate::opts::{Coloring, Expand};
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+// Expand, then poll the sources for changes and expand again, until killed.
+// A proc macro crate is usually a path dependency of the crate using it, or a
+// member of the same workspace, so both get watched.
+pub fn run(
+    args: &Expand,
+    mut config: Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+) -> Result<i32> {
+    // Redrawing has to be able to replace the previous output.
+    config.pager = false;
+
+    let sources = cache::sources(args);
+    let build_dirs = cache::build_dirs(args, &sources);
+    let mut last_fingerprint = None;
+    loop {
+        let fingerprint = fingerprint(&sources.paths, &build_dirs);
+        if last_fingerprint != Some(fingerprint) {
+            last_fingerprint = Some(fingerprint);
+            if io::stdout().is_terminal() {
+                let _ = write!(io::stdout(), "\x1b[2J\x1b[3J\x1b[H");
+                let _ = io::stdout().flush();
+            }
+            if let Err(err) = crate::expand_and_print(args, &config, rustfmt.clone(), color) {
+                let _ = writeln!(io::stderr(), "ERROR: {}", err);
+            }
+            let _ = writeln!(io::stderr(), "Watching for changes...");
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn fingerprint(sources: &[PathBuf], build_dirs: &[PathBuf]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    cache::fingerprint(sources, build_dirs, &mut hasher);
+    hasher.finish()
+}
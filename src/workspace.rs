@@ -0,0 +1,123 @@
+use crate::config::Config;
+use crate::error::Result;
+use crate::metadata::{self, Package, Target};
+use crate::opts::{Coloring, Expand};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+// Expand every selected target of every selected package, one `cargo rustc`
+// at a time, and print them together under a header per target.
+pub fn expand_each(
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<PathBuf>,
+    color: Coloring,
+) -> Result<i32> {
+    let metadata = metadata::load(args)?;
+    let packages: Vec<&Package> = if args.workspace {
+        metadata
+            .packages
+            .iter()
+            .filter(|package| metadata.workspace_members.contains(&package.id))
+            .collect()
+    } else {
+        let Some(package) = metadata.selected_package(args) else {
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: --all-targets requires selecting a single package with -p",
+            );
+            return Ok(1);
+        };
+        vec![package]
+    };
+
+    let mut content = String::new();
+    let mut exit_code = 0;
+    for package in packages {
+        for target in targets(package, args) {
+            let target_args = select(args, package, target);
+            let (expanded, code) = crate::expand(&target_args, config, rustfmt.clone(), color)?;
+            if code != 0 {
+                exit_code = code;
+            }
+            let Some(expanded) = expanded else {
+                continue;
+            };
+            if !content.is_empty() {
+                content.push('\n');
+            }
+            let _ = writeln!(content, "// ==== {} ====", label(package, target));
+            content.push_str(&expanded);
+        }
+    }
+
+    if content.is_empty() {
+        return Ok(if exit_code == 0 { 1 } else { exit_code });
+    }
+    crate::print_expanded(&content, args, config, color)?;
+    Ok(exit_code)
+}
+
+fn targets<'a>(package: &'a Package, args: &Expand) -> Vec<&'a Target> {
+    if args.all_targets {
+        return package
+            .targets
+            .iter()
+            .filter(|target| target.is_lib() || kind(target).is_some())
+            .collect();
+    }
+    if let Some(target) = package.selected_target(args) {
+        return vec![target];
+    }
+    let explicit = args.lib
+        || args.bin.is_some()
+        || args.example.is_some()
+        || args.test.is_some()
+        || args.bench.is_some();
+    if explicit {
+        return Vec::new();
+    }
+    // No lib and more than one bin.
+    package
+        .targets
+        .iter()
+        .filter(|target| kind(target) == Some("bin"))
+        .collect()
+}
+
+fn kind(target: &Target) -> Option<&'static str> {
+    ["bin", "example", "test", "bench"]
+        .into_iter()
+        .find(|&kind| target.kind.iter().any(|k| k == kind))
+}
+
+// The same args, selecting exactly this package and target.
+pub fn select(args: &Expand, package: &Package, target: &Target) -> Expand {
+    let mut args = args.clone();
+    args.workspace = false;
+    args.all_targets = false;
+    args.package = Some(Some(format!("{}@{}", package.name, package.version)));
+    args.lib = false;
+    args.bin = None;
+    args.example = None;
+    args.test = None;
+    args.bench = None;
+    let name = Some(Some(target.name.clone()));
+    match kind(target) {
+        Some("bin") => args.bin = name,
+        Some("example") => args.example = name,
+        Some("test") => args.test = name,
+        Some("bench") => args.bench = name,
+        _ => args.lib = true,
+    }
+    args
+}
+
+// Like cargo's `my-crate (lib)` and `my-crate (bin "name")`.
+fn label(package: &Package, target: &Target) -> String {
+    match kind(target) {
+        Some(kind) => format!("{} ({} {:?})", package.name, kind, target.name),
+        None => format!("{} (lib)", package.name),
+    }
+}